@@ -1,5 +1,3 @@
-use std::cmp::Ordering;
-use std::fmt;
 use std::io;
 use std::path::PathBuf;
 
@@ -9,42 +7,7 @@ use hr_id::Id;
 use rand::Rng;
 use safecast::as_type;
 use tokio::fs;
-use txfs::Dir;
-
-#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
-struct TxnId(u64);
-
-impl PartialEq<str> for TxnId {
-    fn eq(&self, other: &str) -> bool {
-        if let Ok(other) = other.parse() {
-            self.0 == other
-        } else {
-            false
-        }
-    }
-}
-
-impl PartialOrd<str> for TxnId {
-    fn partial_cmp(&self, other: &str) -> Option<Ordering> {
-        if let Ok(other) = other.parse() {
-            self.0.partial_cmp(&other)
-        } else {
-            None
-        }
-    }
-}
-
-impl freqfs::Name for TxnId {
-    fn partial_cmp(&self, key: &String) -> Option<Ordering> {
-        freqfs::Name::partial_cmp(&self.0, key)
-    }
-}
-
-impl fmt::Display for TxnId {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
-    }
-}
+use txfs::{CounterTxnId, Dir, TxnIdSource};
 
 #[derive(Clone)]
 enum File {
@@ -77,9 +40,12 @@ async fn setup_tmp_dir() -> Result<PathBuf, io::Error> {
 }
 
 async fn run_example(cache: DirLock<File>) -> Result<(), txfs::Error> {
-    let first_txn = TxnId(1);
-    let second_txn = TxnId(2);
-    let third_txn = TxnId(3);
+    // a source of unique, increasing transaction IDs which can be shared across threads
+    let txn_ids = TxnIdSource::<CounterTxnId>::default();
+
+    let first_txn = txn_ids.next();
+    let second_txn = txn_ids.next();
+    let third_txn = txn_ids.next();
 
     let root = Dir::load(first_txn, cache).await?;
 
@@ -111,7 +77,7 @@ async fn run_example(cache: DirLock<File>) -> Result<(), txfs::Error> {
     assert!(root.try_get_file(second_txn, &file_one).is_err());
 
     // committing a Dir with recursive=true commits all its children
-    root.commit(first_txn, true).await;
+    root.commit(first_txn, true).await?;
 
     let subdir = root.create_dir(second_txn, subdir_name.clone()).await?;
 
@@ -119,18 +85,18 @@ async fn run_example(cache: DirLock<File>) -> Result<(), txfs::Error> {
         .create_file(second_txn, file_two.clone(), vec![2, 3, 4])
         .await?;
 
-    root.commit(second_txn, true).await;
+    root.commit(second_txn, true).await?;
 
     // deleting a directory will delete all its children, recursively
     root.delete(third_txn, subdir_name.clone()).await?;
 
     // accessing "subdir" after this can cause the filesystem to get out of sync with the cache!
-    root.commit(third_txn, true).await;
+    root.commit(third_txn, true).await?;
 
     // call "finalize" to drop all information about commits earlier than the given transaction ID
     root.finalize(third_txn).await;
 
-    let fourth_txn = TxnId(4);
+    let fourth_txn = txn_ids.next();
 
     // anything that was deleted is now safe to re-create
     let subdir = root.create_dir(fourth_txn, subdir_name).await?;
@@ -139,9 +105,9 @@ async fn run_example(cache: DirLock<File>) -> Result<(), txfs::Error> {
         .create_file(fourth_txn, file_two, vec![3, 4, 5])
         .await?;
 
-    root.commit(fourth_txn, true).await;
+    root.commit(fourth_txn, true).await?;
 
-    let fifth_txn = TxnId(5);
+    let fifth_txn = txn_ids.next();
 
     // and access in later transactions
     assert_eq!(&*file.read::<Vec<u8>>(fifth_txn).await?, &[3u8, 4, 5]);
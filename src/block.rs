@@ -0,0 +1,335 @@
+use std::hash::Hash;
+use std::{fmt, io};
+
+use freqfs::{FileSave, Name};
+use hr_id::Id;
+use safecast::AsType;
+
+use super::dir::{Dir, DirEntry};
+use super::Result;
+
+// the name of the file which stores the length of a [`BlockFile`] in bytes
+const LEN: &str = "len";
+
+/// A transactional file whose contents are split into fixed-size blocks of bytes.
+///
+/// Each block is a separate [`crate::File`], so a write transaction only copies the blocks
+/// that it changes, and committing only syncs those blocks with the filesystem.
+/// The length of the file is stored in another [`crate::File`] alongside its blocks,
+/// so that it can be read without listing or reading the blocks.
+pub struct BlockFile<TxnId, FE> {
+    dir: Dir<TxnId, FE>,
+    block_size: usize,
+}
+
+impl<TxnId, FE> Clone for BlockFile<TxnId, FE> {
+    fn clone(&self) -> Self {
+        Self {
+            dir: self.dir.clone(),
+            block_size: self.block_size,
+        }
+    }
+}
+
+impl<TxnId, FE> BlockFile<TxnId, FE> {
+    /// Construct a new [`BlockFile`] which stores its blocks in the given [`Dir`].
+    ///
+    /// The same `block_size` must be used every time the same [`Dir`] is opened as a [`BlockFile`].
+    ///
+    /// Panics: if `block_size` is zero
+    pub fn new(dir: Dir<TxnId, FE>, block_size: usize) -> Self {
+        assert!(block_size > 0, "invalid block size: {}", block_size);
+        Self { dir, block_size }
+    }
+
+    /// Return the size of each block in this [`BlockFile`], in bytes.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Destructure this [`BlockFile`] into the [`Dir`] which contains its blocks.
+    pub fn into_inner(self) -> Dir<TxnId, FE> {
+        self.dir
+    }
+}
+
+impl<TxnId, FE> BlockFile<TxnId, FE>
+where
+    TxnId: Name + Hash + Ord + Copy + fmt::Display + fmt::Debug + Send + Sync + 'static,
+    FE: for<'a> FileSave<'a> + AsType<Vec<u8>> + Clone + Send + Sync + 'static,
+{
+    /// Return the length of this [`BlockFile`] in bytes at `txn_id`.
+    pub async fn len(&self, txn_id: TxnId) -> Result<u64> {
+        let file = if let Some(file) = self.dir.get_file(txn_id, &len_id()).await? {
+            file
+        } else {
+            return Ok(0);
+        };
+
+        let len = file.read::<Vec<u8>>(txn_id).await?;
+        let len = <[u8; 8]>::try_from(len.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid block file length"))?;
+
+        Ok(u64::from_le_bytes(len))
+    }
+
+    /// Return `true` if this [`BlockFile`] is empty at `txn_id`.
+    pub async fn is_empty(&self, txn_id: TxnId) -> Result<bool> {
+        self.len(txn_id).await.map(|len| len == 0)
+    }
+
+    /// Read up to `len` bytes starting at `offset` at `txn_id`.
+    /// The result will be shorter than `len` if the end of the file is reached.
+    pub async fn read(&self, txn_id: TxnId, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+
+        if len == 0 {
+            return Ok(buffer);
+        }
+
+        // the buffer grows with each block read, since the file may be shorter than `len`
+        let end = offset.saturating_add(len as u64);
+        let first = self.block_index(offset);
+        let last = self.block_index(end - 1);
+
+        for i in first..=last {
+            let block = if let Some(file) = self.dir.get_file(txn_id, &block_id(i)).await? {
+                file.read::<Vec<u8>>(txn_id).await?
+            } else {
+                break;
+            };
+
+            let (lo, hi) = self.block_range(i, offset, end);
+            if lo >= block.len() {
+                break;
+            }
+
+            let hi = Ord::min(hi, block.len());
+            buffer.extend_from_slice(&block[lo..hi]);
+
+            if hi < self.block_size {
+                break;
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    /// Write the given `data` starting at `offset` at `txn_id`.
+    ///
+    /// Only the blocks which overlap the written range are copied and modified.
+    /// If `offset` is past the end of the file, the gap is filled with zeros.
+    /// This fails with [`crate::Error::IO`] if the written range would end past [`u64::MAX`].
+    pub async fn write(&self, txn_id: TxnId, offset: u64, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let len = self.len(txn_id).await?;
+        let num_blocks = self.num_blocks(len);
+
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "block file too long"))?;
+
+        let first = self.block_index(offset);
+        let last = self.block_index(end - 1);
+
+        // any existing last block before the written range must be filled up to the block size
+        if num_blocks > 0 && num_blocks - 1 < first {
+            let mut block = self
+                .dir
+                .write_file::<Vec<u8>>(txn_id, &block_id(num_blocks - 1))
+                .await?;
+
+            block.resize(self.block_size, 0);
+        }
+
+        for i in num_blocks..first {
+            let block = vec![0; self.block_size];
+            self.put_block(txn_id, i, block).await?;
+        }
+
+        for i in first..=last {
+            let (lo, hi) = self.block_range(i, offset, end);
+            let start = (i * self.block_size + lo) as u64 - offset;
+            let segment = &data[start as usize..start as usize + (hi - lo)];
+
+            if i < num_blocks {
+                let mut block = self.dir.write_file::<Vec<u8>>(txn_id, &block_id(i)).await?;
+
+                if block.len() < hi {
+                    block.resize(hi, 0);
+                }
+
+                block[lo..hi].copy_from_slice(segment);
+            } else {
+                let mut block = vec![0; lo];
+                block.extend_from_slice(segment);
+                self.put_block(txn_id, i, block).await?;
+            }
+        }
+
+        if end > len {
+            self.store_len(txn_id, end).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Truncate or extend this [`BlockFile`] to `len` bytes at `txn_id`.
+    /// Any new bytes are filled with zeros.
+    ///
+    /// Blocks past the new end of the file are emptied rather than deleted,
+    /// so that a later write in the same transaction can re-use them.
+    pub async fn set_len(&self, txn_id: TxnId, len: u64) -> Result<()> {
+        let current = self.len(txn_id).await?;
+
+        if len > current {
+            // write one block at a time, so that the whole gap is never allocated at once
+            let zeros = vec![0; self.block_size];
+            let mut offset = current;
+
+            while offset < len {
+                let block_end = (self.block_index(offset) as u64 + 1) * self.block_size as u64;
+                let end = Ord::min(len, block_end);
+                self.write(txn_id, offset, &zeros[..(end - offset) as usize])
+                    .await?;
+
+                offset = end;
+            }
+
+            return Ok(());
+        } else if len == current {
+            return Ok(());
+        }
+
+        let num_blocks = self.num_blocks(current);
+        let keep = self.num_blocks(len);
+
+        for i in keep..num_blocks {
+            self.dir
+                .overwrite_file(txn_id, &block_id(i), Vec::<u8>::new())
+                .await?;
+        }
+
+        if keep > 0 {
+            let last_len = len as usize - (keep - 1) * self.block_size;
+            let mut block = self
+                .dir
+                .write_file::<Vec<u8>>(txn_id, &block_id(keep - 1))
+                .await?;
+            block.truncate(last_len);
+        }
+
+        self.store_len(txn_id, len).await
+    }
+
+    // set the length of this file in bytes at `txn_id`
+    async fn store_len(&self, txn_id: TxnId, len: u64) -> Result<()> {
+        let len_id = len_id();
+        let len = len.to_le_bytes().to_vec();
+
+        if self.dir.contains(txn_id, &len_id).await? {
+            self.dir.overwrite_file(txn_id, &len_id, len).await
+        } else {
+            self.dir
+                .create_file(txn_id, len_id, len)
+                .await
+                .map(|_file| ())
+        }
+    }
+
+    // set the contents of block `i`, re-using an empty block if one is already stored there,
+    // since a block can't be deleted and re-created in the same transaction
+    async fn put_block(&self, txn_id: TxnId, i: usize, block: Vec<u8>) -> Result<()> {
+        if self.dir.contains(txn_id, &block_id(i)).await? {
+            self.dir.overwrite_file(txn_id, &block_id(i), block).await
+        } else {
+            self.dir
+                .create_file(txn_id, block_id(i), block)
+                .await
+                .map(|_file| ())
+        }
+    }
+
+    // the number of blocks which contain data in a file of `len` bytes;
+    // there may be more blocks stored, emptied by `set_len` past the end of the file
+    #[inline]
+    fn num_blocks(&self, len: u64) -> usize {
+        (len as usize).div_ceil(self.block_size)
+    }
+
+    #[inline]
+    fn block_index(&self, offset: u64) -> usize {
+        (offset / self.block_size as u64) as usize
+    }
+
+    // the range of block `i` which overlaps the byte range `start..end`
+    #[inline]
+    fn block_range(&self, i: usize, start: u64, end: u64) -> (usize, usize) {
+        let block_start = (i * self.block_size) as u64;
+        let lo = Ord::max(start, block_start) - block_start;
+        let hi = Ord::min(end, block_start + self.block_size as u64) - block_start;
+        (lo as usize, hi as usize)
+    }
+}
+
+impl<TxnId, FE> BlockFile<TxnId, FE>
+where
+    TxnId: Name
+        + PartialOrd<str>
+        + Hash
+        + Copy
+        + Ord
+        + fmt::Display
+        + fmt::Debug
+        + Send
+        + Sync
+        + 'static,
+    FE: for<'a> FileSave<'a> + Clone + Send + Sync + 'static,
+{
+    /// Commit the state of this [`BlockFile`] at `txn_id`.
+    /// Only the blocks modified at `txn_id` will be synchronized with the filesystem.
+    pub async fn commit(&self, txn_id: TxnId) -> Result<()> {
+        self.dir.commit(txn_id, true).await
+    }
+
+    /// Roll back the state of this [`BlockFile`] at `txn_id`.
+    pub async fn rollback(&self, txn_id: TxnId) {
+        self.dir.rollback(txn_id, true).await
+    }
+
+    /// Finalize the state of this [`BlockFile`] and all its blocks at `txn_id`.
+    pub async fn finalize(&self, txn_id: TxnId) -> Result<()> {
+        for (_name, block) in self.dir.iter(txn_id).await? {
+            if let DirEntry::File(file) = &*block {
+                file.finalize(txn_id).await;
+            }
+        }
+
+        self.dir.finalize(txn_id).await;
+
+        Ok(())
+    }
+}
+
+impl<TxnId, FE> fmt::Debug for BlockFile<TxnId, FE> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "block file with block size {} in {:?}",
+            self.block_size, self.dir
+        )
+    }
+}
+
+#[inline]
+fn block_id(i: usize) -> Id {
+    Id::from(i)
+}
+
+#[inline]
+fn len_id() -> Id {
+    LEN.parse().expect("block file length")
+}
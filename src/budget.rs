@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The size in bytes of an uncommitted version charged to a transaction,
+/// which can be updated if the version is modified
+pub(crate) type Charge = Arc<AtomicUsize>;
+
+// the charges for the uncommitted versions written by one transaction, by path
+type Charges = HashMap<Arc<PathBuf>, Charge>;
+
+fn total<'a>(charges: impl IntoIterator<Item = &'a Charge>) -> usize {
+    charges
+        .into_iter()
+        .map(|charge| charge.load(Ordering::Relaxed))
+        .sum()
+}
+
+/// Accounts for the memory used by the uncommitted versions written by each pending transaction
+pub(crate) struct Budget<TxnId> {
+    limit: Option<usize>,
+    usage: Mutex<HashMap<TxnId, Charges>>,
+}
+
+impl<TxnId> Budget<TxnId> {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<TxnId: Copy + Hash + Eq> Budget<TxnId> {
+    /// Charge `txn_id` for a new version of `size` bytes at `path`, replacing any prior charge
+    /// for the same path, or return `None` if this would exceed the limit.
+    pub fn reserve(&self, txn_id: TxnId, path: &Arc<PathBuf>, size: usize) -> Option<Charge> {
+        let mut usage = self.usage.lock().expect("memory budget");
+
+        if let Some(limit) = self.limit {
+            let used = usage.get(&txn_id).map_or(0, |charges| {
+                total(
+                    charges
+                        .iter()
+                        .filter(|(charged, _)| *charged != path)
+                        .map(|(_, charge)| charge),
+                )
+            });
+
+            if used + size > limit {
+                return None;
+            }
+        }
+
+        let charge = Arc::new(AtomicUsize::new(size));
+        let charges = usage.entry(txn_id).or_default();
+        charges.insert(path.clone(), charge.clone());
+        Some(charge)
+    }
+
+    /// Return the charge for the version at `path` written by `txn_id`, if any.
+    pub fn charged(&self, txn_id: &TxnId, path: &Arc<PathBuf>) -> Option<Charge> {
+        let usage = self.usage.lock().expect("memory budget");
+        usage.get(txn_id)?.get(path).cloned()
+    }
+
+    /// Return `true` if `txn_id` uses more memory than the limit allows.
+    pub fn is_exceeded(&self, txn_id: &TxnId) -> bool {
+        self.limit.is_some_and(|limit| self.usage(txn_id) > limit)
+    }
+
+    /// Drop the charge for the version at `path` written by `txn_id`, if any.
+    pub fn free(&self, txn_id: &TxnId, path: &Arc<PathBuf>) {
+        let mut usage = self.usage.lock().expect("memory budget");
+
+        if let Some(charges) = usage.get_mut(txn_id) {
+            charges.remove(path);
+
+            if charges.is_empty() {
+                usage.remove(txn_id);
+            }
+        }
+    }
+
+    /// Return the number of bytes used by the uncommitted versions written by `txn_id`.
+    pub fn usage(&self, txn_id: &TxnId) -> usize {
+        let usage = self.usage.lock().expect("memory budget");
+        usage
+            .get(txn_id)
+            .map_or(0, |charges| total(charges.values()))
+    }
+
+    /// Return the number of bytes used by the uncommitted versions of each pending transaction.
+    pub fn usage_by_txn(&self) -> Vec<(TxnId, usize)> {
+        let usage = self.usage.lock().expect("memory budget");
+
+        usage
+            .iter()
+            .map(|(txn_id, charges)| (*txn_id, total(charges.values())))
+            .collect()
+    }
+}
+
+impl<TxnId: Copy + Hash + Ord> Budget<TxnId> {
+    /// Drop every charge to a transaction earlier than `txn_id`, e.g. for a version of a file
+    /// which was deleted before it could be committed.
+    pub fn finalize(&self, txn_id: &TxnId) {
+        let mut usage = self.usage.lock().expect("memory budget");
+        usage.retain(|pending, _| pending >= txn_id);
+    }
+}
@@ -0,0 +1,117 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// distinguishes the temporary files of concurrent writers of the same checksum
+static NEXT_PARTIAL: AtomicUsize = AtomicUsize::new(0);
+
+// the lookup table of the CRC-32 (IEEE 802.3) checksum
+static TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+fn update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, byte| {
+        TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+// the path of the file which stores the checksum of the canonical file at `path`
+fn sidecar(path: &Path) -> PathBuf {
+    let name = path.file_name().expect("file name").to_string_lossy();
+    path.with_file_name(format!(".{name}.crc32"))
+}
+
+// compute the CRC-32 checksum of the contents of the file at `path`
+async fn compute(path: &Path) -> io::Result<u32> {
+    let mut file = fs::File::open(path).await?;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut crc = !0;
+
+    loop {
+        let len = file.read(&mut buffer).await?;
+
+        if len == 0 {
+            break Ok(!crc);
+        }
+
+        crc = update(crc, &buffer[..len]);
+    }
+}
+
+/// Record the checksum of the canonical file at `path`, or remove its checksum if it was deleted.
+pub(crate) async fn record(path: &Path) -> io::Result<()> {
+    let sidecar = sidecar(path);
+
+    let crc = match compute(path).await {
+        Ok(crc) => crc,
+        Err(cause) if cause.kind() == io::ErrorKind::NotFound => return remove(path).await,
+        Err(cause) => return Err(cause),
+    };
+
+    // write the new checksum to a temporary file first, so that it's replaced atomically;
+    // two syncs of the same file can overlap, so each one needs its own temporary file
+    let partial = NEXT_PARTIAL.fetch_add(1, Ordering::Relaxed);
+    let partial = sidecar.with_extension(format!("crc32.{partial}.partial"));
+    let mut file = fs::File::create(&partial).await?;
+    file.write_all(format!("{crc:08x}\n").as_bytes()).await?;
+    file.sync_all().await?;
+
+    fs::rename(&partial, &sidecar).await?;
+
+    // make the rename durable
+    let parent = sidecar.parent().expect("parent dir");
+    fs::File::open(parent).await?.sync_all().await
+}
+
+/// Remove the recorded checksum of the canonical file at `path`, if any.
+pub(crate) async fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(sidecar(path)).await {
+        Err(cause) if cause.kind() != io::ErrorKind::NotFound => Err(cause),
+        _ => Ok(()),
+    }
+}
+
+/// Return `false` if the canonical file at `path` doesn't match its recorded checksum.
+/// A file with no recorded checksum, or with a checksum which can't be parsed, is assumed valid.
+pub(crate) async fn verify(path: &Path) -> io::Result<bool> {
+    let expected = match fs::read_to_string(sidecar(path)).await {
+        Ok(expected) => expected,
+        Err(cause) if cause.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(cause) => return Err(cause),
+    };
+
+    // e.g. a sidecar which was only partly written before a crash
+    match u32::from_str_radix(expected.trim(), 16) {
+        Ok(expected) => compute(path).await.map(|actual| actual == expected),
+        Err(_cause) => Ok(true),
+    }
+}
@@ -1,20 +1,29 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::TryLockError;
 use std::hash::Hash;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use std::{fmt, io};
 
 use freqfs::{DirLock, FileLoad, FileSave, Name};
-use futures::future::{self, try_join_all, Future, TryFutureExt};
-use futures::stream::{self, FuturesUnordered, Stream, StreamExt};
+use futures::future::{self, try_join_all, Future, FutureExt, TryFutureExt};
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt, TryStreamExt};
 use get_size::GetSize;
 use hr_id::Id;
 use safecast::AsType;
+use tokio::fs;
 use txn_lock::map::{
     Entry as TxnMapEntry, Iter, TxnMapLock, TxnMapValueReadGuard, TxnMapValueReadGuardMap,
 };
 
+use super::external::{ExternalChange, ExternalWatcher};
 use super::file::*;
-use super::{Error, Result};
+use super::lock::{LockContext, LockPolicy};
+use super::sync::{Durability, Syncs};
+use super::{Error, Location, Result};
 
 /// The name of an entry in a [`Dir`], used to avoid unnecessary allocations
 pub type Key = txn_lock::map::Key<Id>;
@@ -22,6 +31,250 @@ pub type Key = txn_lock::map::Key<Id>;
 /// The name of the directory where un-committed file versions are cached
 pub const VERSIONS: &str = ".txfs";
 
+/// The name of the advisory lock file in the root directory of a transactional filesystem
+pub const LOCK: &str = ".txfs.lock";
+
+// the name of the directory under the root versions directory where new files are staged
+const STAGING: &str = ".staging";
+
+/// The default maximum number of sibling entries to load concurrently
+pub const DEFAULT_LOAD_CONCURRENCY: usize = 16;
+
+/// Options to configure how a [`Dir`] is loaded
+pub struct DirOptions<FE> {
+    load_concurrency: usize,
+    lazy_load: bool,
+    validate_name: Option<fn(&Id) -> bool>,
+    lock_timeout: Option<Duration>,
+    optimistic: bool,
+    group_commit: Option<(Duration, usize)>,
+    durability: Durability,
+    memory_limit: Option<usize>,
+    read_only: bool,
+    checksums: bool,
+    // the directory which caches the file versions of the [`Dir`] with these options, if separate
+    versions_root: Option<DirLock<FE>>,
+}
+
+impl<FE> Clone for DirOptions<FE> {
+    fn clone(&self) -> Self {
+        Self {
+            load_concurrency: self.load_concurrency,
+            lazy_load: self.lazy_load,
+            validate_name: self.validate_name,
+            lock_timeout: self.lock_timeout,
+            optimistic: self.optimistic,
+            group_commit: self.group_commit,
+            durability: self.durability,
+            memory_limit: self.memory_limit,
+            read_only: self.read_only,
+            checksums: self.checksums,
+            versions_root: self.versions_root.clone(),
+        }
+    }
+}
+
+impl<FE> fmt::Debug for DirOptions<FE> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DirOptions")
+            .field("load_concurrency", &self.load_concurrency)
+            .field("lazy_load", &self.lazy_load)
+            .field("validate_name", &self.validate_name)
+            .field("lock_timeout", &self.lock_timeout)
+            .field("optimistic", &self.optimistic)
+            .field("group_commit", &self.group_commit)
+            .field("durability", &self.durability)
+            .field("memory_limit", &self.memory_limit)
+            .field("read_only", &self.read_only)
+            .field("checksums", &self.checksums)
+            .field("versions_root", &self.versions_root)
+            .finish()
+    }
+}
+
+impl<FE> Default for DirOptions<FE> {
+    fn default() -> Self {
+        Self {
+            load_concurrency: DEFAULT_LOAD_CONCURRENCY,
+            lazy_load: false,
+            validate_name: None,
+            lock_timeout: None,
+            optimistic: false,
+            group_commit: None,
+            durability: Durability::Sync,
+            memory_limit: None,
+            read_only: false,
+            checksums: false,
+            versions_root: None,
+        }
+    }
+}
+
+impl<FE> DirOptions<FE> {
+    /// Set the maximum number of sibling entries to load concurrently.
+    /// This limit applies to the whole tree, not separately to each directory being loaded.
+    ///
+    /// Panics: if `max_concurrency` is zero
+    pub fn load_concurrency(mut self, max_concurrency: usize) -> Self {
+        assert!(
+            max_concurrency > 0,
+            "invalid config for load_concurrency: {}",
+            max_concurrency
+        );
+
+        self.load_concurrency = max_concurrency;
+        self
+    }
+
+    /// Don't copy the canonical version of each file in the loaded [`Dir`] (recursively)
+    /// into its versions directory at load time, only the first time the file is accessed.
+    /// This makes loading a large [`Dir`] much faster, at the cost of making the first
+    /// synchronous access to each file (e.g. [`File::try_read`]) fail with [`Error::WouldBlock`].
+    /// This is disabled by default.
+    pub fn lazy_load(mut self, lazy: bool) -> Self {
+        self.lazy_load = lazy;
+        self
+    }
+
+    /// Only allow entries in the loaded [`Dir`] (recursively) whose names pass the given
+    /// `validate` function. Creating an entry with any other name fails with
+    /// [`Error::InvalidName`], as does loading a [`Dir`] which already contains one.
+    /// By default, any valid [`Id`] is allowed.
+    pub fn validate_names(mut self, validate: fn(&Id) -> bool) -> Self {
+        self.validate_name = Some(validate);
+        self
+    }
+
+    /// Fail with [`Error::Timeout`] if any lock needed by an operation on the loaded [`Dir`]
+    /// (recursively) cannot be acquired within the given `timeout`, including the locks on its
+    /// entries in the cache and, in optimistic mode, the permission to validate a commit.
+    /// Once a commit has been validated, installing it, rolling back and finalizing
+    /// never time out. By default, lock acquisition never times out.
+    ///
+    /// There is no separate deadlock detection, since transactions can't wait for each other
+    /// in a cycle: a transactional lock only ever makes a transaction wait for an earlier one,
+    /// and an earlier transaction which tries to write anything a later transaction has already
+    /// locked fails with [`Error::Conflict`] instead of waiting. In optimistic mode, the
+    /// permission to validate a commit is shared by every transaction, but it's only taken
+    /// once the commit no longer has to wait for an earlier transaction, so it can't close
+    /// a cycle either. So a timeout only bounds how long a transaction waits for an earlier one,
+    /// or for another task in the same transaction which holds a lock on the same entry in the
+    /// cache.
+    ///
+    /// This only covers the locks taken by txfs itself: a caller which makes an earlier
+    /// transaction wait for a later one by other means, e.g. by awaiting the later commit
+    /// before committing or rolling back the earlier transaction, can still deadlock,
+    /// and only a timeout will end that wait.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    /// Use optimistic concurrency control for the contents of the files in the loaded [`Dir`]
+    /// (recursively). Reads and writes of file contents don't take any transactional lock;
+    /// instead, each write goes into a version private to its transaction, and committing
+    /// the transaction fails with [`Error::Conflict`] if another transaction committed a version
+    /// of any file it read which should have been visible to it, or if another transaction
+    /// committed a version of, or a later transaction read, any file it wrote.
+    /// Directory entries are still locked as usual, so transactions remain serializable
+    /// (see the crate-level documentation). This is disabled by default.
+    pub fn optimistic(mut self, optimistic: bool) -> Self {
+        self.optimistic = optimistic;
+        self
+    }
+
+    /// Make the commits to the loaded [`Dir`] (recursively) durable in groups: the commits which
+    /// arrive within `window` of the first commit in a group, up to `max_batch` commits,
+    /// are synchronized with the filesystem together, so that each modified file or directory
+    /// is synchronized once per group rather than once per commit. Each commit still only
+    /// completes once its own changes are durable. This is disabled by default.
+    ///
+    /// Panics: if `max_batch` is zero
+    pub fn group_commit(mut self, window: Duration, max_batch: usize) -> Self {
+        assert!(
+            max_batch > 0,
+            "invalid config for group_commit: max_batch {}",
+            max_batch
+        );
+
+        self.group_commit = Some((window, max_batch));
+        self
+    }
+
+    /// Set the default [`Durability`] of a commit to the loaded [`Dir`] (recursively),
+    /// which can be overridden for a single commit with [`Dir::commit_with`]
+    /// or [`File::commit_with`]. The default is [`Durability::Sync`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Limit the memory used by the uncommitted versions of the files in the loaded [`Dir`]
+    /// (recursively) written by any one transaction to `max_bytes`, as measured by [`GetSize`].
+    /// A write which would exceed this limit fails with [`Error::MemoryLimit`], as does any
+    /// further write by a transaction whose versions have grown beyond it. A transaction's
+    /// usage is released when it's committed or rolled back. By default there is no limit.
+    pub fn txn_memory_limit(mut self, max_bytes: usize) -> Self {
+        self.memory_limit = Some(max_bytes);
+        self
+    }
+
+    /// Mount the loaded [`Dir`] (recursively) read-only, e.g. to serve a dataset from a
+    /// read-only volume or snapshot. Nothing is created, modified or synchronized on disk:
+    /// there is no [`VERSIONS`] directory, each [`File`] is read directly from its canonical
+    /// version, and any mutation (e.g. [`Dir::create_file`], [`Dir::delete`] or [`File::write`])
+    /// fails with [`Error::ReadOnly`]. A commit still succeeds, since a read-only transaction
+    /// has no changes to commit, and only commits the in-memory state, so the transaction which
+    /// loads the [`Dir`] should be committed as usual. This includes the [`LOCK`] file: if it
+    /// already exists and can be opened, the mount takes a shared lock on it, so that other
+    /// processes can mount the same root read-only at the same time, but not for writing.
+    /// Otherwise the root is mounted without a lock. This is disabled by default.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Record a checksum of each canonical [`File`] in the loaded [`Dir`] (recursively) in a
+    /// hidden sidecar file next to it whenever a commit synchronizes it with the filesystem,
+    /// and verify it when the [`File`] is loaded (or first accessed, if loaded lazily).
+    /// A [`File`] whose contents don't match its checksum fails to load with [`Error::Corrupt`].
+    /// A [`File`] with no checksum, or with a checksum which can't be parsed (e.g. one which was
+    /// only partly written before a crash), is assumed to be valid, including a [`File`]
+    /// committed with [`Durability::None`] until a later commit synchronizes it.
+    /// A checksum which exists but can't be read fails to load with [`Error::IO`].
+    /// This is disabled by default.
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Cache the uncommitted versions of the files in the loaded [`Dir`] (recursively) under
+    /// the separate `versions` root (e.g. on a tmpfs or NVMe volume) rather than in a [`VERSIONS`]
+    /// directory inside each canonical directory.
+    ///
+    /// The layout of `versions` mirrors the layout of the loaded [`Dir`], i.e. the versions of
+    /// a file at a path relative to the [`Dir`] are cached in the directory at the same path
+    /// relative to `versions`. Any existing contents of `versions` are deleted, so it must not be
+    /// shared with any other [`Dir`], and it should be loaded from the same [`freqfs::Cache`]
+    /// as the [`Dir`]. The [`DirOptions`] of each sub-directory refer to its own versions root.
+    pub fn versions_root(mut self, versions: DirLock<FE>) -> Self {
+        self.versions_root = Some(versions);
+        self
+    }
+}
+
+impl<FE> DirOptions<FE> {
+    fn is_valid(&self, name: &Id) -> bool {
+        self.validate_name.is_none_or(|validate| validate(name))
+    }
+}
+
+/// An entry in a [`Dir`] which has been discovered but not yet loaded
+enum PendingEntry<FE> {
+    Dir(DirLock<FE>, Option<DirLock<FE>>),
+    File(DirLock<FE>),
+}
+
 /// An entry in a [`Dir`]
 pub enum DirEntry<TxnId, FE> {
     Dir(Dir<TxnId, FE>),
@@ -40,18 +293,12 @@ impl<TxnId, FE> Clone for DirEntry<TxnId, FE> {
 impl<TxnId, FE> DirEntry<TxnId, FE> {
     /// Return `true` if this [`DirEntry`] is itself a [`Dir`].
     pub fn is_dir(&self) -> bool {
-        match self {
-            Self::Dir(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::Dir(_))
     }
 
     /// Return `true` if this [`DirEntry`] is a [`File`].
     fn is_file(&self) -> bool {
-        match self {
-            Self::File(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::File(_))
     }
 }
 
@@ -64,11 +311,91 @@ impl<TxnId, FE> fmt::Debug for DirEntry<TxnId, FE> {
     }
 }
 
+// the committed entries of a [`Dir`], which can be listed without a transactional lock
+struct Listing<TxnId, FE> {
+    canon: Arc<BTreeMap<Id, DirEntry<TxnId, FE>>>,
+    commits: BTreeMap<TxnId, HashMap<Id, Option<DirEntry<TxnId, FE>>>>,
+    finalized: Option<TxnId>,
+}
+
+impl<TxnId: Ord, FE> Listing<TxnId, FE> {
+    fn new(canon: BTreeMap<Id, DirEntry<TxnId, FE>>) -> Self {
+        Self {
+            canon: Arc::new(canon),
+            commits: BTreeMap::new(),
+            finalized: None,
+        }
+    }
+
+    // return the entries committed as of `txn_id`, or `None` if `txn_id` is already finalized;
+    // this shares the finalized entries unless a later commit has to be applied to a copy of them
+    fn snapshot(&self, txn_id: &TxnId) -> Option<Arc<BTreeMap<Id, DirEntry<TxnId, FE>>>> {
+        if self.finalized.as_ref() > Some(txn_id) {
+            return None;
+        }
+
+        let mut commits = self.commits.range(..=txn_id).peekable();
+
+        if commits.peek().is_none() {
+            return Some(self.canon.clone());
+        }
+
+        let mut entries = BTreeMap::clone(&self.canon);
+
+        for (_commit_id, delta) in commits {
+            for (name, entry) in delta {
+                if let Some(entry) = entry {
+                    entries.insert(name.clone(), entry.clone());
+                } else {
+                    entries.remove(name);
+                }
+            }
+        }
+
+        Some(Arc::new(entries))
+    }
+}
+
+impl<TxnId: Ord + Copy, FE> Listing<TxnId, FE> {
+    fn finalize(&mut self, txn_id: TxnId, canon: BTreeMap<Id, DirEntry<TxnId, FE>>) {
+        self.canon = Arc::new(canon);
+        self.commits.retain(|commit_id, _| *commit_id > txn_id);
+        self.finalized = Some(txn_id);
+    }
+}
+
+// an iterator over a snapshot of a [`Listing`], which clones each entry only as it's visited
+struct SnapshotIter<TxnId, FE> {
+    entries: Arc<BTreeMap<Id, DirEntry<TxnId, FE>>>,
+    last: Option<Id>,
+}
+
+impl<TxnId, FE> Iterator for SnapshotIter<TxnId, FE> {
+    type Item = (Id, DirEntry<TxnId, FE>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut range = match &self.last {
+            Some(last) => self.entries.range::<Id, _>((Excluded(last), Unbounded)),
+            None => self.entries.range::<Id, _>(..),
+        };
+
+        let (name, entry) = range.next()?;
+        let next = (name.clone(), entry.clone());
+        self.last = Some(name.clone());
+        Some(next)
+    }
+}
+
 /// A transactional directory
 pub struct Dir<TxnId, FE> {
     canon: DirLock<FE>,
     versions: DirLock<FE>,
     entries: TxnMapLock<TxnId, Id, DirEntry<TxnId, FE>>,
+    listing: Arc<RwLock<Listing<TxnId, FE>>>,
+    lock: LockContext<TxnId>,
+    options: DirOptions<FE>,
+    // the root dir keeps its [`VERSIONS`] dir so that freqfs never deletes it, nor its [`LOCK`]
+    root: bool,
 }
 
 impl<TxnId, FE> Clone for Dir<TxnId, FE> {
@@ -77,6 +404,10 @@ impl<TxnId, FE> Clone for Dir<TxnId, FE> {
             canon: self.canon.clone(),
             versions: self.versions.clone(),
             entries: self.entries.clone(),
+            listing: self.listing.clone(),
+            lock: self.lock.clone(),
+            options: self.options.clone(),
+            root: self.root,
         }
     }
 }
@@ -88,15 +419,46 @@ where
     /// Destructure this [`Dir`] into its underlying [`DirLock`].
     /// The caller of this method must implement transactional state management explicitly.
     pub fn into_inner(self) -> DirLock<FE> {
-        debug_assert!(self.canon.try_read().expect("canon").contains(VERSIONS));
+        debug_assert!(
+            self.options.versions_root.is_some()
+                || self.options.read_only
+                || self.canon.try_read().expect("canon").contains(VERSIONS)
+        );
+
         self.canon
     }
 }
 
-impl<TxnId: Copy + Hash + Eq + Ord + fmt::Debug, FE> Dir<TxnId, FE> {
+impl<TxnId: Copy + Hash + Eq + Ord + fmt::Display + fmt::Debug, FE> Dir<TxnId, FE> {
+    /// Return the [`DirOptions`] this [`Dir`] was loaded with.
+    pub fn options(&self) -> &DirOptions<FE> {
+        &self.options
+    }
+
+    // fail with `Error::InvalidName` if this `Dir` doesn't allow an entry with the given `name`
+    fn validate_name(&self, txn_id: &TxnId, name: &Id) -> Result<()> {
+        if self.options.is_valid(name) {
+            Ok(())
+        } else {
+            Err(Error::InvalidName(self.lock.location_of(txn_id, name)))
+        }
+    }
+
+    /// Return the number of bytes used by the uncommitted versions of files written by `txn_id`
+    /// anywhere in the filesystem which this [`Dir`] belongs to.
+    pub fn memory_usage(&self, txn_id: TxnId) -> usize {
+        self.lock.memory_usage(txn_id)
+    }
+
+    /// Return the number of bytes used by the uncommitted versions of files written by each
+    /// pending transaction anywhere in the filesystem which this [`Dir`] belongs to.
+    pub fn memory_usage_by_txn(&self) -> Vec<(TxnId, usize)> {
+        self.lock.memory_usage_by_txn()
+    }
+
     /// Return `true` if there is at least one [`File`] in this [`Dir`] at `txn_id`.
     pub async fn contains_files(&self, txn_id: TxnId) -> Result<bool> {
-        let entries = self.entries.iter(txn_id).await?;
+        let entries = self.lock.acquire(txn_id, self.entries.iter(txn_id)).await?;
 
         for (_, entry) in entries {
             if entry.is_file() {
@@ -109,12 +471,14 @@ impl<TxnId: Copy + Hash + Eq + Ord + fmt::Debug, FE> Dir<TxnId, FE> {
 
     /// Return the number of entries in this [`Dir`] as of the given `txn_id`.
     pub async fn len(&self, txn_id: TxnId) -> Result<usize> {
-        self.entries.len(txn_id).map_err(Error::from).await
+        self.lock.acquire(txn_id, self.entries.len(txn_id)).await
     }
 
     /// Return `true` if this [`Dir`] is empty at the given `txn_id`.
     pub async fn is_empty(&self, txn_id: TxnId) -> Result<bool> {
-        self.entries.is_empty(txn_id).map_err(Error::from).await
+        self.lock
+            .acquire(txn_id, self.entries.is_empty(txn_id))
+            .await
     }
 }
 
@@ -123,28 +487,107 @@ where
     TxnId: Name + Hash + Ord + Copy + fmt::Display + fmt::Debug + Send + Sync + 'static,
     FE: for<'a> FileSave<'a> + Clone,
 {
-    /// Load a transactional [`Dir`] from a [`DirLock`].
+    /// Load a transactional [`Dir`] from a [`DirLock`] with the default [`DirOptions`].
     pub fn load(
         txn_id: TxnId,
         canon: DirLock<FE>,
+    ) -> Pin<Box<dyn Future<Output = Result<Self>> + Send>> {
+        Self::load_with(txn_id, canon, DirOptions::default())
+    }
+
+    /// Load a transactional [`Dir`] from a [`DirLock`] with the given `options`.
+    ///
+    /// This takes an advisory lock on a [`LOCK`] file in `canon`, which is held until every
+    /// handle to the loaded [`Dir`] and its contents is dropped, and fails with [`Error::Locked`]
+    /// if another process already holds a conflicting lock on the same root. A read-only mount
+    /// (see [`DirOptions::read_only`]) takes a shared lock on an existing [`LOCK`] file, if any,
+    /// and any other mount takes an exclusive lock, creating the [`LOCK`] file if needed.
+    pub fn load_with(
+        txn_id: TxnId,
+        canon: DirLock<FE>,
+        options: DirOptions<FE>,
+    ) -> Pin<Box<dyn Future<Output = Result<Self>> + Send>> {
+        Self::load_root(txn_id, canon, options)
+    }
+
+    fn load_root(
+        txn_id: TxnId,
+        canon: DirLock<FE>,
+        options: DirOptions<FE>,
+    ) -> Pin<Box<dyn Future<Output = Result<Self>> + Send>> {
+        Box::pin(async move {
+            let path = canon.read().await.path().to_path_buf();
+            let root_lock = lock_root(&txn_id, &path, &options).await?;
+
+            let mut policy = LockPolicy::new(
+                options.lock_timeout,
+                options.optimistic,
+                options.group_commit,
+                options.durability,
+                options.memory_limit,
+                options.read_only,
+            )
+            .with_checksums(options.checksums)
+            .with_load_concurrency(options.load_concurrency)
+            .with_root_lock(root_lock);
+
+            if !options.read_only {
+                // this is cleared along with the rest of the root versions dir when it's loaded
+                let versions = match &options.versions_root {
+                    Some(versions) => versions.read().await.path().to_path_buf(),
+                    None => path.join(VERSIONS),
+                };
+
+                policy = policy.with_staging_dir(versions.join(STAGING));
+            }
+
+            Self::load_inner(txn_id, canon, options, Arc::new(policy), true).await
+        })
+    }
+
+    // load a [`Dir`] whose file versions are cached in the versions root of its `options`,
+    // if any, or otherwise in a [`VERSIONS`] directory inside `canon`
+    fn load_inner(
+        txn_id: TxnId,
+        canon: DirLock<FE>,
+        options: DirOptions<FE>,
+        policy: Arc<LockPolicy<TxnId>>,
+        root: bool,
     ) -> Pin<Box<dyn Future<Output = Result<Self>> + Send>> {
         #[cfg(feature = "log")]
         log::debug!("load transactional dir from {:?}", canon);
 
         Box::pin(async move {
-            let (contents, versions) = {
-                #[cfg(feature = "log")]
-                log::trace!("lock canonical dir for writing");
-
-                let versions = {
-                    let mut dir = canon.write().await;
-                    dir.get_or_create_dir(VERSIONS.to_string())?
+            #[cfg(feature = "log")]
+            log::trace!("lock canonical dir for writing");
+
+            let (versions, lock) = if options.read_only {
+                // a read-only dir never writes a version, so its files are read from `canon`
+                let dir = canon.read().await;
+                let lock = LockContext::new(policy.clone(), dir.path().to_path_buf());
+                (canon.clone(), lock)
+            } else {
+                let mut dir = canon.write().await;
+
+                let versions = match &options.versions_root {
+                    Some(versions) => versions.clone(),
+                    None => dir.get_or_create_dir(VERSIONS.to_string())?,
                 };
 
-                let contents = {
-                    #[cfg(feature = "log")]
-                    log::trace!("lock version dir for writing");
+                let lock = LockContext::new(policy.clone(), dir.path().to_path_buf());
+                (versions, lock)
+            };
+
+            let pending = {
+                // this permit is released before any entry in this dir is loaded
+                let _permit = lock.load_permit().await;
+
+                #[cfg(feature = "log")]
+                log::trace!("lock version dir for writing");
 
+                let mut versions = if options.read_only {
+                    None
+                } else {
                     let mut versions = versions.write().await;
 
                     #[cfg(feature = "logging")]
@@ -152,57 +595,120 @@ where
                     versions.truncate().await;
                     versions.sync().await?;
 
-                    let mut contents = HashMap::new();
+                    Some(versions)
+                };
 
-                    for (name, entry) in canon.try_read()?.iter() {
-                        let name: Id = if name.starts_with('.') {
-                            #[cfg(feature = "logging")]
-                            log::trace!("skipping hidden dir entry {name}");
-                            continue;
-                        } else {
-                            name.parse()?
-                        };
+                let canon_lock = canon.clone();
+                let canon = canon.try_read()?;
+                let mut pending = Vec::with_capacity(canon.len());
 
-                        let entry = match entry.clone() {
-                            freqfs::DirEntry::Dir(dir) => {
-                                #[cfg(feature = "log")]
-                                log::trace!("load sub-dir {}: {:?}", name, dir);
-                                Self::load(txn_id, dir).map_ok(DirEntry::Dir).await?
-                            }
-                            freqfs::DirEntry::File(_file) => {
-                                #[cfg(debug_assertions)]
-                                if !_file.path().exists() {
-                                    #[cfg(feature = "log")]
-                                    log::warn!("there is no file at {}", _file.path().display());
-                                }
+                for (name, entry) in canon.iter() {
+                    let name: Id = if name.starts_with('.') {
+                        #[cfg(feature = "logging")]
+                        log::trace!("skipping hidden dir entry {name}");
+                        continue;
+                    } else {
+                        name.parse()?
+                    };
 
-                                #[cfg(feature = "log")]
-                                log::trace!("load file {}: {:?}", name, _file);
+                    if !options.is_valid(&name) {
+                        return Err(Error::InvalidName(lock.location_of(&txn_id, &name)));
+                    }
 
-                                let file_versions = versions.get_or_create_dir(name.to_string())?;
+                    let entry = match entry {
+                        freqfs::DirEntry::Dir(dir) => {
+                            let dir_versions = match &mut versions {
+                                Some(versions) if options.versions_root.is_some() => {
+                                    Some(versions.get_or_create_dir(name.to_string())?)
+                                }
+                                _ => None,
+                            };
 
+                            PendingEntry::Dir(dir.clone(), dir_versions)
+                        }
+                        freqfs::DirEntry::File(_file) => {
+                            #[cfg(debug_assertions)]
+                            if !_file.path().exists() {
                                 #[cfg(feature = "log")]
-                                log::trace!("created versions dir for file {}: {:?}", name, _file);
-
-                                File::load(txn_id, name.clone(), canon.clone(), file_versions)
-                                    .map_ok(DirEntry::File)
-                                    .await?
+                                log::warn!("there is no file at {}", _file.path().display());
                             }
-                        };
 
-                        contents.insert(name, entry);
-                    }
+                            let file_versions = match &mut versions {
+                                Some(versions) => versions.get_or_create_dir(name.to_string())?,
+                                None => canon_lock.clone(),
+                            };
 
-                    contents
-                };
+                            #[cfg(feature = "log")]
+                            log::trace!("created versions dir for file {}: {:?}", name, _file);
+
+                            PendingEntry::File(file_versions)
+                        }
+                    };
+
+                    pending.push((name, entry));
+                }
 
-                (contents, versions)
+                pending
             };
 
+            // siblings don't share any locks, so it's safe to load them concurrently;
+            // the load permits of the lock policy bound the concurrency of the whole tree
+            let loads = pending.into_iter().map(|(name, entry)| {
+                let canon = canon.clone();
+                let options = options.clone();
+                let policy = policy.clone();
+                let lock = lock.child(&name);
+
+                async move {
+                    let entry = match entry {
+                        PendingEntry::Dir(dir, dir_versions) => {
+                            #[cfg(feature = "log")]
+                            log::trace!("load sub-dir {}: {:?}", name, dir);
+
+                            let options = DirOptions {
+                                versions_root: dir_versions,
+                                ..options
+                            };
+
+                            Self::load_inner(txn_id, dir, options, policy, false)
+                                .map_ok(DirEntry::Dir)
+                                .await?
+                        }
+                        PendingEntry::File(file_versions) => {
+                            #[cfg(feature = "log")]
+                            log::trace!("load file {}", name);
+
+                            let lazy = options.lazy_load;
+                            let _permit = lock.load_permit().await;
+
+                            File::load(txn_id, name.clone(), canon, file_versions, lock, lazy)
+                                .map_ok(DirEntry::File)
+                                .await?
+                        }
+                    };
+
+                    Result::Ok((name, entry))
+                }
+            });
+
+            let contents: HashMap<Id, DirEntry<TxnId, FE>> = stream::iter(loads)
+                .buffer_unordered(options.load_concurrency)
+                .try_collect()
+                .await?;
+
+            let listing = contents
+                .iter()
+                .map(|(name, entry)| (name.clone(), entry.clone()))
+                .collect();
+
             Ok(Self {
                 canon,
                 versions,
+                listing: Arc::new(RwLock::new(Listing::new(listing))),
                 entries: TxnMapLock::with_contents(txn_id, contents),
+                lock,
+                options,
+                root,
             })
         })
     }
@@ -212,21 +718,38 @@ where
         #[cfg(feature = "logging")]
         log::trace!("Dir::create_dir {name}");
 
-        let entry = match self.entries.entry(txn_id, name.clone()).await? {
+        self.lock.check_writable(&txn_id)?;
+        self.validate_name(&txn_id, &name)?;
+
+        let entry = match self
+            .lock
+            .acquire(txn_id, self.entries.entry(txn_id, name.clone()))
+            .await?
+        {
             TxnMapEntry::Occupied(_) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::AlreadyExists,
-                    format!("directory {name}"),
-                )
-                .into())
+                return Err(Error::AlreadyExists(self.lock.location_of(&txn_id, &name)))
             }
             TxnMapEntry::Vacant(entry) => entry,
         };
 
-        let mut canon = self.canon.write().await;
+        let mut canon = self.lock.wait(&txn_id, self.canon.write()).await?;
 
         let sub_dir = canon.get_or_create_dir(name.to_string())?;
-        let sub_dir = Self::load(txn_id, sub_dir).await?;
+
+        let versions_root = if self.options.versions_root.is_some() {
+            let mut versions = self.lock.wait(&txn_id, self.versions.write()).await?;
+            Some(versions.get_or_create_dir(name.to_string())?)
+        } else {
+            None
+        };
+
+        let options = DirOptions {
+            versions_root,
+            ..self.options.clone()
+        };
+
+        let policy = self.lock.policy().clone();
+        let sub_dir = Self::load_inner(txn_id, sub_dir, options, policy, false).await?;
 
         entry.insert(DirEntry::Dir(sub_dir.clone()));
 
@@ -241,15 +764,17 @@ where
 {
     /// Return `true` if this [`Dir`] has an entry at the given `name` at `txn_id`.
     pub async fn contains(&self, txn_id: TxnId, name: &Id) -> Result<bool> {
-        self.entries
-            .contains_key(txn_id, name)
-            .map_err(Error::from)
-            .await
+        let contains = self.entries.contains_key(txn_id, name);
+        self.lock.acquire(txn_id, contains).await
     }
 
     /// Delete the entry at `name` at `txn_id` and return `true` if it was present.
     pub async fn delete(&self, txn_id: TxnId, name: Id) -> Result<bool> {
-        if let Some(entry) = self.entries.remove(txn_id, &name).await? {
+        self.lock.check_writable(&txn_id)?;
+
+        let removed = self.entries.remove(txn_id, &name);
+
+        if let Some(entry) = self.lock.acquire(txn_id, removed).await? {
             if let DirEntry::Dir(dir) = &*entry {
                 dir.clone().truncate(txn_id).await?;
             }
@@ -260,15 +785,35 @@ where
         }
     }
 
+    /// Delete the entry at `name` at `txn_id` synchronously, if possible,
+    /// and return `true` if it was present.
+    pub fn try_delete(&self, txn_id: TxnId, name: Id) -> Result<bool> {
+        self.lock.check_writable(&txn_id)?;
+
+        if let Some(entry) = self
+            .entries
+            .try_remove(txn_id, &name)
+            .map_err(|cause| self.lock.error(&txn_id, cause))?
+        {
+            if let DirEntry::Dir(dir) = &*entry {
+                dir.try_truncate(txn_id)?;
+            }
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     /// Construct an iterator over the names of the sub-directories in this [`Dir`] at `txn_id`.
     pub async fn dir_names(&self, txn_id: TxnId) -> Result<impl Iterator<Item = Key>> {
-        let iterator = self.entries.iter(txn_id).await?;
+        let iterator = self.lock.acquire(txn_id, self.entries.iter(txn_id)).await?;
         Ok(iterator.filter_map(|(name, entry)| if entry.is_dir() { Some(name) } else { None }))
     }
 
     /// Construct an iterator over the names of the files in this [`Dir`] at `txn_id`.
     pub async fn file_names(&self, txn_id: TxnId) -> Result<impl Iterator<Item = Key>> {
-        let iterator = self.entries.iter(txn_id).await?;
+        let iterator = self.lock.acquire(txn_id, self.entries.iter(txn_id)).await?;
         Ok(iterator.filter_map(|(name, entry)| if entry.is_file() { Some(name) } else { None }))
     }
 
@@ -281,7 +826,7 @@ where
         FE: AsType<F>,
         F: FileLoad,
     {
-        let entries = self.entries.iter(txn_id).await?;
+        let entries = self.lock.acquire(txn_id, self.entries.iter(txn_id)).await?;
         let files = entries.filter_map(|(name, entry)| match &*entry {
             DirEntry::File(file) => Some((name, file.clone())),
             _ => None,
@@ -294,8 +839,39 @@ where
     }
 
     /// Construct an iterator over the contents of this [`Dir`] at `txn_id`.
+    ///
+    /// This locks the entire listing of this [`Dir`] for reading at `txn_id`, so it waits for any
+    /// pending change in an earlier transaction, and any later change in an earlier transaction
+    /// will fail with [`Error::Conflict`]. See [`Self::iter_snapshot`] for an alternative.
     pub async fn iter(&self, txn_id: TxnId) -> Result<Iter<TxnId, Id, DirEntry<TxnId, FE>>> {
-        self.entries.iter(txn_id).map_err(Error::from).await
+        self.lock.acquire(txn_id, self.entries.iter(txn_id)).await
+    }
+
+    /// Construct an iterator over the committed contents of this [`Dir`] as of `txn_id`,
+    /// in order of their names.
+    ///
+    /// Unlike [`Self::iter`], this doesn't acquire any transactional lock: it never waits, and it
+    /// doesn't prevent an earlier transaction from creating or deleting an entry. This means that
+    /// it's not serializable: it only reflects the transactions which had already been committed
+    /// when it was called, and it doesn't include any uncommitted change made at `txn_id` itself.
+    ///
+    /// The entries finalized so far are shared rather than copied, but any commit after the last
+    /// finalized transaction (up to `txn_id`) is applied to a new copy of the whole listing.
+    ///
+    /// This fails with [`Error::Outdated`] if a later transaction has already been finalized.
+    pub fn iter_snapshot(
+        &self,
+        txn_id: TxnId,
+    ) -> Result<impl Iterator<Item = (Id, DirEntry<TxnId, FE>)>> {
+        let listing = self.listing.read().expect("listing");
+
+        listing
+            .snapshot(&txn_id)
+            .map(|entries| SnapshotIter {
+                entries,
+                last: None,
+            })
+            .ok_or_else(|| Error::Outdated(self.lock.location(&txn_id)))
     }
 
     /// Get a sub-directory in this [`Dir`] at the given `txn_id`.
@@ -304,8 +880,10 @@ where
         txn_id: TxnId,
         name: &Id,
     ) -> Result<Option<TxnMapValueReadGuardMap<Id, Self>>> {
-        if let Some(entry) = self.entries.get(txn_id, name).map_err(Error::from).await? {
-            expect_dir(entry).map(Some)
+        let entry = self.entries.get(txn_id, name);
+
+        if let Some(entry) = self.lock.acquire(txn_id, entry).await? {
+            expect_dir(entry, || self.lock.location_of(&txn_id, name)).map(Some)
         } else {
             Ok(None)
         }
@@ -317,8 +895,12 @@ where
         txn_id: TxnId,
         name: &Id,
     ) -> Result<Option<TxnMapValueReadGuardMap<Id, Self>>> {
-        if let Some(entry) = self.entries.try_get(txn_id, name).map_err(Error::from)? {
-            expect_dir(entry).map(Some)
+        if let Some(entry) = self
+            .entries
+            .try_get(txn_id, name)
+            .map_err(|cause| self.lock.error(&txn_id, cause))?
+        {
+            expect_dir(entry, || self.lock.location_of(&txn_id, name)).map(Some)
         } else {
             Ok(None)
         }
@@ -327,11 +909,16 @@ where
     /// Delete the contents of this [`Dir`] at `txn_id`.
     pub fn truncate(self, txn_id: TxnId) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
         Box::pin(async move {
-            let entries = self.entries.clear(txn_id).map_err(Error::from).await?;
+            self.lock.check_writable(&txn_id)?;
+
+            let entries = self
+                .lock
+                .acquire(txn_id, self.entries.clear(txn_id))
+                .await?;
 
             let truncates = entries
-                .into_iter()
-                .filter_map(|(_name, entry)| {
+                .into_values()
+                .filter_map(|entry| {
                     if let DirEntry::Dir(dir) = &*entry {
                         Some(dir.clone())
                     } else {
@@ -343,6 +930,93 @@ where
             try_join_all(truncates).map_ok(|_| ()).await
         })
     }
+
+    /// Delete the contents of this [`Dir`] at `txn_id` synchronously, if possible.
+    pub fn try_truncate(&self, txn_id: TxnId) -> Result<()> {
+        self.lock.check_writable(&txn_id)?;
+
+        let entries = self
+            .entries
+            .try_clear(txn_id)
+            .map_err(|cause| self.lock.error(&txn_id, cause))?;
+
+        for entry in entries.into_values() {
+            if let DirEntry::Dir(dir) = &*entry {
+                dir.try_truncate(txn_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check every [`File`] in this [`Dir`] (recursively) committed as of `txn_id` for changes
+    /// to its canonical version made outside of the transactional filesystem since it was loaded
+    /// or last committed, e.g. by another process.
+    ///
+    /// Like [`Self::iter_snapshot`], this doesn't acquire any transactional lock.
+    /// A file committed with [`Durability::None`] is not checked until a later commit
+    /// synchronizes it with the filesystem.
+    pub async fn verify_external(&self, txn_id: TxnId) -> Result<Vec<ExternalChange<TxnId, FE>>> {
+        let mut changes = Vec::new();
+        self.collect_external(txn_id, &mut changes).await?;
+        Ok(changes)
+    }
+
+    fn collect_external<'a>(
+        &'a self,
+        txn_id: TxnId,
+        changes: &'a mut Vec<ExternalChange<TxnId, FE>>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let entries = self.iter_snapshot(txn_id)?.collect::<Vec<_>>();
+
+            for (name, entry) in entries {
+                match entry {
+                    DirEntry::Dir(dir) => dir.collect_external(txn_id, changes).await?,
+                    DirEntry::File(file) => {
+                        if let Some(kind) = file.check_external().await? {
+                            let path = self.lock.path().join(name.as_str());
+                            changes.push(ExternalChange::new(path, kind, file));
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Call [`Self::verify_external`] in the background every `interval`, at the transaction
+    /// returned by `txn_id`, and pass the result to `on_change` if any change is detected
+    /// or the check fails.
+    ///
+    /// The returned [`ExternalWatcher`] holds a handle to this [`Dir`] and stops when dropped.
+    /// This must be called from within a Tokio runtime.
+    pub fn watch_external<T, C>(
+        &self,
+        interval: Duration,
+        txn_id: T,
+        mut on_change: C,
+    ) -> ExternalWatcher
+    where
+        T: Fn() -> TxnId + Send + 'static,
+        C: FnMut(Result<Vec<ExternalChange<TxnId, FE>>>) + Send + 'static,
+    {
+        let dir = self.clone();
+
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                match dir.verify_external(txn_id()).await {
+                    Ok(changes) if changes.is_empty() => {}
+                    result => on_change(result),
+                }
+            }
+        });
+
+        ExternalWatcher::new(task)
+    }
 }
 
 impl<TxnId, FE> Dir<TxnId, FE>
@@ -364,24 +1038,79 @@ where
         #[cfg(feature = "logging")]
         log::trace!("Dir::create_file {name}");
 
+        self.lock.check_writable(&txn_id)?;
+        self.validate_name(&txn_id, &name)?;
+
         // this write permit ensures that there is no other pending entry with this name
-        let entry = match self.entries.entry(txn_id, name.clone()).await? {
+        let entry = match self
+            .lock
+            .acquire(txn_id, self.entries.entry(txn_id, name.clone()))
+            .await?
+        {
             TxnMapEntry::Occupied(_) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::AlreadyExists,
-                    format!("directory {name}"),
-                )
-                .into())
+                return Err(Error::AlreadyExists(self.lock.location_of(&txn_id, &name)))
             }
             TxnMapEntry::Vacant(entry) => entry,
         };
 
         let versions = {
-            let mut versions = self.versions.write().await;
+            let mut versions = self.lock.wait(&txn_id, self.versions.write()).await?;
+            versions.get_or_create_dir(name.to_string())?
+        };
+
+        let lock = self.lock.child(&name);
+        let file = File::create(txn_id, name, self.canon.clone(), versions, lock, contents).await?;
+
+        entry.insert(DirEntry::File(file.clone()));
+
+        Ok(file)
+    }
+
+    /// Create a new [`File`] with the given `name`, `contents` at `txn_id` synchronously,
+    /// if possible.
+    pub fn try_create_file<F>(
+        &self,
+        txn_id: TxnId,
+        name: Id,
+        contents: F,
+    ) -> Result<File<TxnId, FE>>
+    where
+        FE: AsType<F>,
+        F: GetSize + Clone,
+    {
+        #[cfg(feature = "logging")]
+        log::trace!("Dir::try_create_file {name}");
+
+        self.lock.check_writable(&txn_id)?;
+        self.validate_name(&txn_id, &name)?;
+
+        // this write permit ensures that there is no other pending entry with this name
+        let entry = match self.entries.entry(txn_id, name.clone()).now_or_never() {
+            Some(Ok(TxnMapEntry::Occupied(_))) => {
+                return Err(Error::AlreadyExists(self.lock.location_of(&txn_id, &name)))
+            }
+            Some(Ok(TxnMapEntry::Vacant(entry))) => entry,
+            Some(Err(cause)) => return Err(self.lock.error(&txn_id, cause)),
+            None => return Err(Error::WouldBlock(self.lock.location_of(&txn_id, &name))),
+        };
+
+        let versions = {
+            let mut versions = self
+                .versions
+                .try_write()
+                .map_err(|cause| self.lock.io_error(&txn_id, cause))?;
             versions.get_or_create_dir(name.to_string())?
         };
 
-        let file = File::create(txn_id, name, self.canon.clone(), versions, contents).await?;
+        let lock = self.lock.child(&name);
+        let file = File::try_create(
+            txn_id,
+            name.clone(),
+            self.canon.clone(),
+            versions,
+            lock,
+            contents,
+        )?;
 
         entry.insert(DirEntry::File(file.clone()));
 
@@ -394,8 +1123,10 @@ where
         txn_id: TxnId,
         name: &Id,
     ) -> Result<Option<TxnMapValueReadGuardMap<Id, File<TxnId, FE>>>> {
-        if let Some(entry) = self.entries.get(txn_id, name).map_err(Error::from).await? {
-            expect_file(entry).map(Some)
+        let entry = self.entries.get(txn_id, name);
+
+        if let Some(entry) = self.lock.acquire(txn_id, entry).await? {
+            expect_file(entry, || self.lock.location_of(&txn_id, name)).map(Some)
         } else {
             Ok(None)
         }
@@ -407,8 +1138,12 @@ where
         txn_id: TxnId,
         name: &Id,
     ) -> Result<Option<TxnMapValueReadGuardMap<Id, File<TxnId, FE>>>> {
-        if let Some(entry) = self.entries.try_get(txn_id, name).map_err(Error::from)? {
-            expect_file(entry).map(Some)
+        if let Some(entry) = self
+            .entries
+            .try_get(txn_id, name)
+            .map_err(|cause| self.lock.error(&txn_id, cause))?
+        {
+            expect_file(entry, || self.lock.location_of(&txn_id, name)).map(Some)
         } else {
             Ok(None)
         }
@@ -428,7 +1163,7 @@ where
         if let Some(file) = self.get_file(txn_id, name).await? {
             file.read(txn_id).await
         } else {
-            Err(io::Error::new(io::ErrorKind::NotFound, format!("file not found: {name}")).into())
+            Err(Error::NotFound(self.lock.location_of(&txn_id, name)))
         }
     }
 
@@ -440,74 +1175,258 @@ where
         name: &Id,
     ) -> Result<FileVersionWrite<TxnId, FE, F>>
     where
+        TxnId: Send + Sync + 'static,
         F: FileLoad + GetSize + Clone,
         FE: for<'a> FileSave<'a> + AsType<F>,
     {
         if let Some(file) = self.get_file(txn_id, name).await? {
             file.write(txn_id).await
         } else {
-            Err(io::Error::new(io::ErrorKind::NotFound, format!("file not found: {name}")).into())
+            Err(Error::NotFound(self.lock.location_of(&txn_id, name)))
+        }
+    }
+
+    /// Convenience method to replace the contents of a file in this [`Dir`] at the given `txn_id`
+    /// without reading its current contents.
+    /// This returns an error if the file is not found.
+    pub async fn overwrite_file<F>(&self, txn_id: TxnId, name: &Id, contents: F) -> Result<()>
+    where
+        F: GetSize,
+        FE: AsType<F>,
+    {
+        if let Some(file) = self.get_file(txn_id, name).await? {
+            file.overwrite(txn_id, contents).await
+        } else {
+            Err(Error::NotFound(self.lock.location_of(&txn_id, name)))
         }
     }
 }
 
 impl<TxnId, FE> Dir<TxnId, FE>
 where
-    TxnId: Name + PartialOrd<str> + Hash + Copy + Ord + fmt::Debug + Send + Sync,
+    TxnId: Name + PartialOrd<str> + Hash + Copy + Ord + fmt::Display + fmt::Debug + Send + Sync,
     FE: for<'a> FileSave<'a> + Clone,
 {
     /// Commit the state of this [`Dir`] at `txn_id`.
+    ///
+    /// In optimistic mode, if `recursive` is `true`, every file in this [`Dir`] (recursively)
+    /// is validated before anything is committed, and this fails with [`Error::Conflict`]
+    /// if any of them was committed by another transaction after `txn_id` first read or wrote it.
+    /// Before that, it waits for any earlier transaction which is still creating or deleting
+    /// an entry in this [`Dir`], like a commit in pessimistic mode.
+    ///
+    /// If this fails with [`Error::IO`], `txn_id` was committed but synchronizing it with the
+    /// filesystem failed, so it must not be rolled back. Any other error (e.g. [`Error::Conflict`]
+    /// or [`Error::Timeout`]) means that nothing was committed and `txn_id` should be rolled back.
+    ///
+    /// This completes once the committed state is as durable as the default [`Durability`]
+    /// of this [`Dir`] requires (see [`DirOptions::durability`]). With group commit enabled
+    /// (see [`DirOptions::group_commit`]) that may mean waiting for other commits in its group.
     pub fn commit<'a>(
         &'a self,
         txn_id: TxnId,
         recursive: bool,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        self.commit_with(txn_id, recursive, self.lock.durability())
+    }
+
+    /// Commit the state of this [`Dir`] at `txn_id` with the given `durability`.
+    /// See [`Dir::commit`] for details.
+    pub fn commit_with<'a>(
+        &'a self,
+        txn_id: TxnId,
+        recursive: bool,
+        durability: Durability,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let syncs = Syncs::default();
+
+            if self.lock.is_read_only() {
+                // a read-only transaction has no changes to validate or synchronize
+                self.commit_inner(txn_id, recursive, &syncs).await;
+                return Ok(());
+            }
+
+            if self.lock.is_optimistic() {
+                self.wait_for_entries(txn_id, recursive).await?;
+            }
+
+            {
+                let _permit = self.lock.wait(&txn_id, self.lock.commit_permit()).await?;
+
+                if recursive && self.lock.is_optimistic() {
+                    self.validate(txn_id)?;
+                }
+
+                self.commit_inner(txn_id, recursive, &syncs).await;
+            }
+
+            self.lock.sync(syncs, durability).await.map_err(Error::from)
+        })
+    }
+
+    // wait for any earlier transaction which is still creating or deleting an entry in this dir
+    // (recursively, if `recursive`), since committing `txn_id` has to wait for it,
+    // and it must not wait while holding the commit permit which the earlier commit needs
+    fn wait_for_entries(
+        &self,
+        txn_id: TxnId,
+        recursive: bool,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            let dirs = self
+                .lock
+                .acquire(txn_id, self.entries.iter(txn_id))
+                .await?
+                .filter_map(|(_name, entry)| match &*entry {
+                    DirEntry::Dir(dir) if recursive => Some(dir.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+
+            for dir in dirs {
+                dir.wait_for_entries(txn_id, recursive).await?;
+            }
+
+            Ok(())
+        })
+    }
+
+    // check that every file in this dir (recursively) can be committed at `txn_id`;
+    // this visits the committed listing rather than locking the entries at `txn_id`, so that
+    // it never waits for another transaction while holding the commit permit, nor conflicts
+    // with one, and a file created at `txn_id` can't have been observed by any other transaction
+    fn validate(&self, txn_id: TxnId) -> Result<()> {
+        let entries = self
+            .listing
+            .read()
+            .expect("listing")
+            .snapshot(&txn_id)
+            .ok_or_else(|| Error::Outdated(self.lock.location(&txn_id)))?;
+
+        for entry in entries.values() {
+            match entry {
+                DirEntry::Dir(dir) => dir.validate(txn_id)?,
+                DirEntry::File(file) => file.validate(txn_id)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    // commit the state of this dir at `txn_id`, which must already be validated
+    fn commit_inner<'a>(
+        &'a self,
+        txn_id: TxnId,
+        recursive: bool,
+        syncs: &'a Syncs,
     ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
         Box::pin(async move {
             #[cfg(feature = "logging")]
             log::trace!("Dir::commit, recursive={recursive}");
 
-            let (contents, deltas) = self.entries.read_and_commit(txn_id).await;
+            // wait for any earlier transaction which is still creating or deleting an entry,
+            // so that committing the entries won't wait while holding the canonical write lock
+            let entries = self
+                .entries
+                .iter(txn_id)
+                .await
+                .expect("entries")
+                .map(|(name, entry)| (Id::clone(&name), DirEntry::clone(&*entry)))
+                .collect::<Vec<_>>();
+
+            // the new canonical version of each file in this dir modified at `txn_id`,
+            // which has to be copied before the file is committed and released to a later
+            // transaction, so that the later transaction's version can't be overwritten
+            let mut modified = Vec::new();
 
             if recursive {
-                let commits = FuturesUnordered::new();
+                let versions = FuturesUnordered::new();
+
+                for (_name, entry) in &entries {
+                    if let DirEntry::File(file) = entry {
+                        versions.push(async move {
+                            let version = file.written_version(txn_id).await?;
+                            Some((file, version))
+                        });
+                    }
+                }
 
-                for (_name, entry) in &contents {
-                    #[cfg(feature = "logging")]
-                    log::trace!("Dir::commit {}: {:?}", _name, entry);
+                modified = versions.filter_map(future::ready).collect().await;
+            }
 
-                    let entry = DirEntry::clone(&*entry);
+            {
+                // apply every change to the canonical dir under a single write lock, which is
+                // also held while committing the entries, for the same reason
+                let mut canon = if self.lock.is_read_only() {
+                    None
+                } else {
+                    Some(self.canon.write().await)
+                };
 
-                    commits.push(async move {
-                        match entry {
-                            DirEntry::Dir(dir) => dir.commit(txn_id, recursive).await,
-                            DirEntry::File(file) => file.commit(txn_id).await,
-                        }
-                    });
+                let (contents, deltas) = self.entries.read_and_commit(txn_id).await;
+
+                if let Some(deltas) = &deltas {
+                    let delta = deltas
+                        .iter()
+                        .map(|(name, entry)| {
+                            let entry = entry.as_ref().map(|entry| DirEntry::clone(entry));
+                            (Id::clone(name), entry)
+                        })
+                        .collect();
+
+                    let mut listing = self.listing.write().expect("listing");
+                    listing.commits.insert(txn_id, delta);
                 }
 
-                commits.fold((), |(), ()| future::ready(())).await;
-            }
+                if let Some(canon) = &mut canon {
+                    for (file, version) in modified {
+                        file.copy_to_canon(txn_id, &version, canon, syncs).await;
+                    }
 
-            let mut needs_sync = false;
-            if let Some(deltas) = deltas {
-                let mut canon = self.canon.write().await;
+                    let mut needs_sync = false;
+                    for (name, entry) in deltas.into_iter().flatten() {
+                        if entry.is_none() {
+                            assert!(!contents.contains_key(&*name));
 
-                for (name, entry) in deltas {
-                    if entry.is_none() {
-                        assert!(!contents.contains_key(&*name));
+                            // note: freqfs::DirEntry::is_file also returns true for a directory
+                            match canon.get(&*name) {
+                                Some(freqfs::DirEntry::File(file)) => {
+                                    // remove the canonical version of a file deleted in this txn
+                                    syncs.file(file.clone(), None, self.lock.checksums());
+                                }
+                                Some(freqfs::DirEntry::Dir(_)) => needs_sync = true,
+                                None => {}
+                            }
 
-                        if let Some(entry) = canon.get(&*name) {
-                            needs_sync = needs_sync || entry.is_file();
+                            canon.delete(&*name).await;
                         }
+                    }
 
-                        canon.delete(&*name).await;
+                    if needs_sync {
+                        // remove any sub-directory deleted in this transaction,
+                        // so it can be re-created
+                        syncs.dir(self.lock.path().clone(), self.canon.clone());
                     }
                 }
-            };
+            }
+
+            if recursive {
+                let commits = FuturesUnordered::new();
+
+                for (_name, entry) in entries {
+                    #[cfg(feature = "logging")]
+                    log::trace!("Dir::commit {:?}", entry);
+
+                    commits.push(async move {
+                        match entry {
+                            DirEntry::Dir(dir) => dir.commit_inner(txn_id, recursive, syncs).await,
+                            DirEntry::File(file) => file.commit_inner(txn_id).await,
+                        }
+                    });
+                }
 
-            if needs_sync {
-                // remove the canonical version of any file that was deleted in this transaction
-                self.canon.sync().await.expect("sync");
+                commits.fold((), |(), ()| future::ready(())).await;
             }
         })
     }
@@ -542,12 +1461,30 @@ where
 
     /// Finalize the state of this [`Dir`] at `txn_id`.
     pub async fn finalize(&self, txn_id: TxnId) {
+        // drop the memory charged for any version deleted before it could be committed
+        self.lock.finalize_budget(txn_id);
+
         let mut sync_canon = false;
 
         if let Some(entries) = self.entries.read_and_finalize(txn_id) {
+            {
+                let canon = entries
+                    .iter()
+                    .map(|(name, entry)| (Id::clone(name), DirEntry::clone(entry)))
+                    .collect();
+
+                let mut listing = self.listing.write().expect("listing");
+                listing.finalize(txn_id, canon);
+            }
+
+            if self.lock.is_read_only() {
+                // there are no deleted entries or obsolete versions to clean up
+                return;
+            }
+
             let names = entries
-                .into_iter()
-                .map(|(name, _)| name.to_string())
+                .into_keys()
+                .map(|name| name.to_string())
                 .collect::<HashSet<_>>();
 
             let delete_versions = {
@@ -574,7 +1511,9 @@ where
                     versions.delete(name.as_str()).await;
                 }
 
-                versions.is_empty()
+                // a separate versions dir is not inside the canonical dir, and deleting
+                // the last entry of the root dir would delete the root dir from the filesystem
+                versions.is_empty() && self.options.versions_root.is_none() && !self.root
             };
 
             let mut canon = self.canon.write().await;
@@ -611,6 +1550,61 @@ where
     }
 }
 
+// take an advisory lock on the root directory at `path`, which is released when the returned
+// file is dropped, or fail with `Error::Locked` if another process holds a conflicting lock
+async fn lock_root<TxnId: fmt::Display, FE>(
+    txn_id: &TxnId,
+    path: &Path,
+    options: &DirOptions<FE>,
+) -> Result<Option<std::fs::File>> {
+    let path = path.join(LOCK);
+
+    let file = if options.read_only {
+        // a read-only mount never writes to disk, so it only locks a lock file which exists
+        match fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(cause) if is_unlockable(&cause) => return Ok(None),
+            Err(cause) => return Err(cause.into()),
+        }
+    } else {
+        create_lock(&path).await?
+    };
+
+    let file = file.into_std().await;
+
+    let locked = if options.read_only {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+
+    match locked {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Err(Error::Locked(Location::new(path, txn_id))),
+        Err(TryLockError::Error(cause)) => Err(cause.into()),
+    }
+}
+
+// return `true` if a read-only mount should proceed without a lock after failing to open
+// the lock file with the given error, e.g. on a snapshot which was never mounted for writing
+fn is_unlockable(cause: &io::Error) -> bool {
+    matches!(
+        cause.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+async fn create_lock(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .await
+}
+
 impl<TxnId, FE> fmt::Debug for Dir<TxnId, FE> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "transactional {:?}", self.canon)
@@ -620,25 +1614,21 @@ impl<TxnId, FE> fmt::Debug for Dir<TxnId, FE> {
 #[inline]
 fn expect_dir<TxnId, FE>(
     entry: TxnMapValueReadGuard<Id, DirEntry<TxnId, FE>>,
+    location: impl FnOnce() -> Location,
 ) -> Result<TxnMapValueReadGuardMap<Id, Dir<TxnId, FE>>> {
     entry.try_map(|entry| match entry {
         DirEntry::Dir(dir) => Ok(dir.clone()),
-        DirEntry::File(file) => Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("not a directory: {:?}", file),
-        )
-        .into()),
+        DirEntry::File(_) => Err(Error::NotADirectory(location())),
     })
 }
 
 #[inline]
 fn expect_file<TxnId, FE>(
     entry: TxnMapValueReadGuard<Id, DirEntry<TxnId, FE>>,
+    location: impl FnOnce() -> Location,
 ) -> Result<TxnMapValueReadGuardMap<Id, File<TxnId, FE>>> {
     entry.try_map(|entry| match entry {
-        DirEntry::Dir(dir) => {
-            Err(io::Error::new(io::ErrorKind::InvalidData, format!("not a file: {:?}", dir)).into())
-        }
+        DirEntry::Dir(_) => Err(Error::NotAFile(location())),
         DirEntry::File(file) => Ok(file.clone()),
     })
 }
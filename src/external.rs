@@ -0,0 +1,202 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tokio::fs;
+use tokio::task::JoinHandle;
+
+use super::file::File;
+
+/// The kind of an [`ExternalChange`]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExternalChangeKind {
+    /// The canonical file was modified outside of the transactional filesystem.
+    Modified,
+    /// The canonical file was deleted outside of the transactional filesystem.
+    Deleted,
+}
+
+/// A change to the canonical version of a [`File`] made outside of the transactional filesystem,
+/// as reported by [`crate::Dir::verify_external`]
+///
+/// To resolve it, either accept the change with [`File::reload`] or revert it with
+/// [`File::restore`] and then commit the same transaction, or delete the [`File`].
+pub struct ExternalChange<TxnId, FE> {
+    path: PathBuf,
+    kind: ExternalChangeKind,
+    file: File<TxnId, FE>,
+}
+
+impl<TxnId, FE> ExternalChange<TxnId, FE> {
+    pub(crate) fn new(path: PathBuf, kind: ExternalChangeKind, file: File<TxnId, FE>) -> Self {
+        Self { path, kind, file }
+    }
+
+    /// The full path of the changed canonical file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The kind of this change.
+    pub fn kind(&self) -> ExternalChangeKind {
+        self.kind
+    }
+
+    /// The changed [`File`].
+    pub fn file(&self) -> &File<TxnId, FE> {
+        &self.file
+    }
+}
+
+impl<TxnId, FE> Clone for ExternalChange<TxnId, FE> {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            kind: self.kind,
+            file: self.file.clone(),
+        }
+    }
+}
+
+impl<TxnId, FE> std::fmt::Debug for ExternalChange<TxnId, FE> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?} {}", self.kind, self.path.display())
+    }
+}
+
+/// A background task started by [`crate::Dir::watch_external`], which stops when dropped
+pub struct ExternalWatcher {
+    task: JoinHandle<()>,
+}
+
+impl ExternalWatcher {
+    pub(crate) fn new(task: JoinHandle<()>) -> Self {
+        Self { task }
+    }
+}
+
+impl Drop for ExternalWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// the state of a canonical file on the host filesystem
+#[derive(Copy, Clone, Eq, PartialEq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Stamp {
+    async fn read(path: &Path) -> io::Result<Option<Self>> {
+        match fs::metadata(path).await {
+            Ok(metadata) => Ok(Some(Self {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            })),
+            Err(cause) if cause.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(cause) => Err(cause),
+        }
+    }
+}
+
+struct State {
+    // incremented each time the canonical file is replaced, so that a stale stamp is never recorded
+    generation: u64,
+    // `None` if the state of the canonical file on the host filesystem is not known
+    stamp: Option<Stamp>,
+}
+
+/// Tracks the last known state of a canonical file on the host filesystem
+#[derive(Clone)]
+pub(crate) struct Tracker {
+    state: Arc<Mutex<State>>,
+}
+
+impl Tracker {
+    /// Construct a new [`Tracker`] for a canonical file which has not been written yet.
+    pub fn unknown() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                generation: 0,
+                stamp: None,
+            })),
+        }
+    }
+
+    /// Construct a new [`Tracker`] for the existing canonical file at `path`.
+    pub async fn load(path: &Path) -> io::Result<Self> {
+        let tracker = Self::unknown();
+        tracker.record(path, 0).await?;
+        Ok(tracker)
+    }
+
+    /// Forget the last known state of the canonical file because it's about to be replaced,
+    /// and return the generation to [`Self::record`] once the replacement is synchronized.
+    pub fn invalidate(&self) -> u64 {
+        let mut state = self.state.lock().expect("file stamp");
+        state.generation += 1;
+        state.stamp = None;
+        state.generation
+    }
+
+    /// Record the current state of the canonical file at `path`,
+    /// unless it's been replaced again since `generation`.
+    pub async fn record(&self, path: &Path, generation: u64) -> io::Result<()> {
+        let stamp = Stamp::read(path).await?;
+
+        let mut state = self.state.lock().expect("file stamp");
+        if state.generation == generation {
+            state.stamp = stamp;
+        }
+
+        Ok(())
+    }
+
+    /// Return `true` if the canonical file at `path` is known to be in its last recorded state,
+    /// i.e. it's been synchronized and hasn't been replaced or changed since.
+    pub async fn is_current(&self, path: &Path) -> io::Result<bool> {
+        let (generation, expected) = {
+            let state = self.state.lock().expect("file stamp");
+
+            match state.stamp {
+                Some(stamp) => (state.generation, stamp),
+                None => return Ok(false),
+            }
+        };
+
+        let actual = Stamp::read(path).await?;
+
+        let state = self.state.lock().expect("file stamp");
+        Ok(state.generation == generation && actual == Some(expected))
+    }
+
+    /// Check whether the canonical file at `path` has been changed outside of the transactional
+    /// filesystem since its state was last recorded, if known.
+    pub async fn check(&self, path: &Path) -> io::Result<Option<ExternalChangeKind>> {
+        let (generation, expected) = {
+            let state = self.state.lock().expect("file stamp");
+
+            match state.stamp {
+                Some(stamp) => (state.generation, stamp),
+                None => return Ok(None),
+            }
+        };
+
+        let actual = Stamp::read(path).await?;
+
+        let state = self.state.lock().expect("file stamp");
+        if state.generation != generation {
+            // the canonical file was replaced in the meantime
+            return Ok(None);
+        }
+
+        match actual {
+            None => Ok(Some(ExternalChangeKind::Deleted)),
+            Some(actual) if actual != expected => Ok(Some(ExternalChangeKind::Modified)),
+            Some(_) => Ok(None),
+        }
+    }
+}
@@ -1,20 +1,193 @@
+use std::collections::HashMap;
 use std::hash::Hash;
-use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
+use std::io::SeekFrom;
+use std::ops::{Deref, DerefMut, Range};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use std::{fmt, io};
 
 use freqfs::*;
 use get_size::GetSize;
 use hr_id::Id;
 use safecast::AsType;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
 use txn_lock::scalar::{TxnLock, TxnLockReadGuard, TxnLockWriteGuard};
 
+use super::budget::Charge;
+use super::checksum;
+use super::external::{ExternalChangeKind, Tracker};
+use super::lock::LockContext;
+use super::sync::{Durability, Syncs};
 use super::{Error, Result};
 
+// distinguishes the staged files of concurrent writers in the same transaction
+static NEXT_STAGED: AtomicUsize = AtomicUsize::new(0);
+
+// a write permit on the last-modified version ID of a [`File`]
+// which discards the new version and points back to the prior version
+// if the new version is never modified
+struct Modified<TxnId> {
+    // in optimistic mode, there is no lock to hold
+    guard: Option<TxnLockWriteGuard<TxnId>>,
+    prior: Option<TxnId>,
+    // releases the memory charged for the new version
+    discard: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl<TxnId> Drop for Modified<TxnId> {
+    fn drop(&mut self) {
+        if let Some(prior) = self.prior.take() {
+            if let Some(discard) = self.discard.take() {
+                discard();
+            }
+
+            if let Some(guard) = &mut self.guard {
+                **guard = prior;
+            }
+        }
+    }
+}
+
+enum Permit<TxnId, FE> {
+    Read(TxnLockReadGuard<TxnId>),
+    Snapshot,
+    // a write lock on the last-modified version ID which hasn't created a new version yet
+    Upgradable(TxnLockWriteGuard<TxnId>),
+    Write(Modified<TxnId>, FileLock<FE>),
+}
+
+// a write guard on a version of a [`File`] which updates the memory charged to its transaction
+// for that version when it's released, if it was modified
+struct VersionWriteGuard<FE, F> {
+    guard: FileWriteGuardOwned<FE, F>,
+    charge: Option<Charge>,
+    measure: fn(&F) -> usize,
+    modified: bool,
+}
+
+impl<FE, F: GetSize> VersionWriteGuard<FE, F> {
+    fn new(guard: FileWriteGuardOwned<FE, F>, charge: Option<Charge>) -> Self {
+        Self {
+            guard,
+            charge,
+            measure: F::get_size,
+            modified: false,
+        }
+    }
+}
+
+impl<FE, F> Deref for VersionWriteGuard<FE, F> {
+    type Target = F;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.deref()
+    }
+}
+
+impl<FE, F> DerefMut for VersionWriteGuard<FE, F> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.modified = true;
+        self.guard.deref_mut()
+    }
+}
+
+impl<FE, F> Drop for VersionWriteGuard<FE, F> {
+    fn drop(&mut self) {
+        if let (true, Some(charge)) = (self.modified, &self.charge) {
+            let size = (self.measure)(&self.guard);
+            charge.store(size, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+// the version of a [`File`] first observed by a pending transaction in optimistic mode
+struct Access<TxnId> {
+    observed: TxnId,
+    // the first version committed after the observed version, if any
+    superseded: Option<TxnId>,
+    written: bool,
+}
+
+// the state of a [`File`] in optimistic mode, used to validate each transaction at commit time
+struct Validation<TxnId> {
+    committed: TxnId,
+    last_read: TxnId,
+    pending: HashMap<TxnId, Access<TxnId>>,
+}
+
+impl<TxnId: Copy + Hash + Eq> Validation<TxnId> {
+    fn new(committed: TxnId) -> Self {
+        Self {
+            committed,
+            last_read: committed,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<TxnId: Hash + Eq> Validation<TxnId> {
+    fn mark_written(&mut self, txn_id: &TxnId) {
+        if let Some(access) = self.pending.get_mut(txn_id) {
+            access.written = true;
+        }
+    }
+}
+
 /// A read guard on a version of a transactional [`File`]
 pub struct FileVersionRead<TxnId, FE, F> {
-    _modified: TxnLockReadGuard<TxnId>,
     version: FileReadGuardOwned<FE, F>,
+    _permit: Permit<TxnId, FE>,
+    file: File<TxnId, FE>,
+    txn_id: TxnId,
+}
+
+impl<TxnId, FE, F> FileVersionRead<TxnId, FE, F>
+where
+    TxnId: Name + fmt::Display + fmt::Debug + Hash + Ord + Copy + Send + Sync + 'static,
+    FE: AsType<F> + Clone + Send + Sync + 'static,
+    F: FileLoad + Clone + GetSize,
+{
+    /// Upgrade this read guard to a write guard on the same [`File`] at the same transaction,
+    /// without releasing its lock in between.
+    ///
+    /// Only a guard which holds the write lock on its [`File`], i.e. one returned by
+    /// [`File::read_upgradable`] or [`FileVersionWrite::downgrade`], or one which holds no lock
+    /// at all, i.e. one read in optimistic mode, can be upgraded. A guard returned by
+    /// [`File::read`] in pessimistic mode returns [`Error::NotUpgradable`], since its read lock
+    /// would have to be released before the write lock could be acquired.
+    pub async fn upgrade(self) -> Result<FileVersionWrite<TxnId, FE, F>> {
+        let Self {
+            file,
+            txn_id,
+            _permit: permit,
+            version,
+        } = self;
+
+        std::mem::drop(version);
+
+        match permit {
+            Permit::Read(_guard) => Err(Error::NotUpgradable(file.lock.location(&txn_id))),
+            Permit::Snapshot => file.write(txn_id).await,
+            Permit::Upgradable(last_modified) => file.write_locked(txn_id, last_modified).await,
+            Permit::Write(modified, lock) => {
+                let charge = file.lock.charge(txn_id)?;
+                let version = file.lock.wait(&txn_id, lock.write_owned()).await??;
+                let version = VersionWriteGuard::new(version, charge);
+
+                Ok(FileVersionWrite {
+                    file,
+                    txn_id,
+                    modified,
+                    lock,
+                    version,
+                })
+            }
+        }
+    }
 }
 
 impl<TxnId, FE, F> Deref for FileVersionRead<TxnId, FE, F> {
@@ -26,9 +199,47 @@ impl<TxnId, FE, F> Deref for FileVersionRead<TxnId, FE, F> {
 }
 
 /// A write guard on a version of a transactional [`File`]
+///
+/// If this guard created a new version of its [`File`] but is dropped without ever being
+/// mutably dereferenced, the memory charged to its transaction for the new version is released,
+/// and the new version is discarded when its transaction is committed or rolled back.
 pub struct FileVersionWrite<TxnId, FE, F> {
-    _modified: TxnLockWriteGuard<TxnId>,
-    version: FileWriteGuardOwned<FE, F>,
+    version: VersionWriteGuard<FE, F>,
+    modified: Modified<TxnId>,
+    lock: FileLock<FE>,
+    file: File<TxnId, FE>,
+    txn_id: TxnId,
+}
+
+impl<TxnId, FE, F> FileVersionWrite<TxnId, FE, F>
+where
+    TxnId: fmt::Display,
+    FE: AsType<F> + Send + Sync,
+    F: FileLoad,
+{
+    /// Downgrade this write guard to a read guard on the same [`File`] at the same transaction.
+    ///
+    /// The write lock is held until the returned read guard is dropped.
+    pub async fn downgrade(self) -> Result<FileVersionRead<TxnId, FE, F>> {
+        let Self {
+            file,
+            txn_id,
+            modified,
+            lock,
+            version,
+        } = self;
+
+        std::mem::drop(version);
+
+        let version = file.lock.wait(&txn_id, lock.read_owned()).await??;
+
+        Ok(FileVersionRead {
+            file,
+            txn_id,
+            _permit: Permit::Write(modified, lock),
+            version,
+        })
+    }
 }
 
 impl<TxnId, FE, F> Deref for FileVersionWrite<TxnId, FE, F> {
@@ -39,15 +250,173 @@ impl<TxnId, FE, F> Deref for FileVersionWrite<TxnId, FE, F> {
     }
 }
 
-impl<TxnId, FE, F> DerefMut for FileVersionWrite<TxnId, FE, F> {
+impl<TxnId, FE, F> DerefMut for FileVersionWrite<TxnId, FE, F>
+where
+    TxnId: Hash + Eq,
+{
     fn deref_mut(&mut self) -> &mut Self::Target {
+        // the new version has been modified, so keep it
+        if self.modified.prior.take().is_some() {
+            self.file.mark_written(&self.txn_id);
+        }
+
         self.version.deref_mut()
     }
 }
 
+/// A streaming reader over a version of a transactional [`File`] on the host filesystem
+pub struct FileVersionReader<TxnId> {
+    _modified: Option<TxnLockReadGuard<TxnId>>,
+    file: fs::File,
+}
+
+impl<TxnId> AsyncRead for FileVersionReader<TxnId>
+where
+    TxnId: Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cxt: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_read(cxt, buf)
+    }
+}
+
+impl<TxnId> AsyncSeek for FileVersionReader<TxnId>
+where
+    TxnId: Unpin,
+{
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.file).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cxt: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.file).poll_complete(cxt)
+    }
+}
+
+/// A streaming writer over a new version of a transactional [`File`] on the host filesystem.
+///
+/// The new contents are staged in a separate file on disk, which is removed when this writer
+/// is dropped, and only replace the version at this writer's transaction when [`Self::finish`]
+/// is called.
+pub struct FileVersionWriter<TxnId, FE> {
+    modified: Option<TxnLockWriteGuard<TxnId>>,
+    lock: LockContext<TxnId>,
+    validation: Option<Arc<Mutex<Validation<TxnId>>>>,
+    txn_id: TxnId,
+    versions: DirLock<FE>,
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl<TxnId, FE> FileVersionWriter<TxnId, FE>
+where
+    TxnId: Name + fmt::Display + Hash + Ord + Copy,
+    FE: for<'a> FileSave<'a> + Clone,
+{
+    /// Truncate or extend the staged contents of this writer to `size` bytes,
+    /// e.g. to replace the current contents with shorter ones.
+    /// This doesn't move the position of the writer.
+    pub async fn set_len(&mut self, size: u64) -> Result<()> {
+        self.file.set_len(size).await.map_err(Error::from)
+    }
+
+    /// Flush the staged contents of this writer to the host filesystem and make them
+    /// the current version of the [`File`] at this writer's transaction.
+    ///
+    /// The new version replaces the prior version on disk without being loaded into the cache,
+    /// so it's only loaded if it's read. Its transaction is charged for its encoded size.
+    pub async fn finish(mut self) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+
+        let size = self.file.metadata().await?.len();
+        self.lock
+            .reserve(self.txn_id, usize::try_from(size).unwrap_or(usize::MAX))?;
+
+        {
+            let staged = FileLock::load::<FE>(self.lock.staging(), self.path.clone());
+            let mut versions = self.lock.wait(&self.txn_id, self.versions.write()).await?;
+
+            // this copies the staged file on disk and evicts any cached copy of the prior version
+            versions
+                .copy_file_from(self.txn_id.to_string(), &staged)
+                .await?;
+        }
+
+        if let Some(modified) = &mut self.modified {
+            **modified = self.txn_id;
+        } else if let Some(validation) = &self.validation {
+            let mut validation = validation.lock().expect("file validation");
+            validation.mark_written(&self.txn_id);
+        }
+
+        Ok(())
+    }
+}
+
+impl<TxnId, FE> Drop for FileVersionWriter<TxnId, FE> {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.path) {
+            Err(cause) if cause.kind() != io::ErrorKind::NotFound => {
+                #[cfg(feature = "logging")]
+                log::warn!("failed to remove {}: {cause}", self.path.display());
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<TxnId, FE> AsyncWrite for FileVersionWriter<TxnId, FE>
+where
+    TxnId: Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cxt: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cxt, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cxt: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cxt)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cxt: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_shutdown(cxt)
+    }
+}
+
+impl<TxnId, FE> AsyncSeek for FileVersionWriter<TxnId, FE>
+where
+    TxnId: Unpin,
+{
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.file).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cxt: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.file).poll_complete(cxt)
+    }
+}
+
 /// A transactional file
 pub struct File<TxnId, FE> {
     last_modified: TxnLock<TxnId, TxnId>,
+    validation: Option<Arc<Mutex<Validation<TxnId>>>>,
+    // if loaded lazily, the ID of the version to copy from the canonical version on first access
+    lazy: Option<Arc<futures::lock::Mutex<Option<TxnId>>>>,
+    // the last known state of the canonical version on the host filesystem
+    tracker: Tracker,
+    // the ID of the version last copied into the canonical version, if any
+    canonical: Arc<Mutex<Option<TxnId>>>,
+    lock: LockContext<TxnId>,
+    // if read-only, this is the same as `parent` and must never be modified
     versions: DirLock<FE>,
     parent: DirLock<FE>,
     name: Arc<Id>,
@@ -57,6 +426,11 @@ impl<TxnId, FE> Clone for File<TxnId, FE> {
     fn clone(&self) -> Self {
         Self {
             last_modified: self.last_modified.clone(),
+            validation: self.validation.clone(),
+            lazy: self.lazy.clone(),
+            tracker: self.tracker.clone(),
+            canonical: self.canonical.clone(),
+            lock: self.lock.clone(),
             versions: self.versions.clone(),
             parent: self.parent.clone(),
             name: self.name.clone(),
@@ -74,6 +448,7 @@ where
         name: Id,
         parent: DirLock<FE>,
         versions: DirLock<FE>,
+        lock: LockContext<TxnId>,
         version: F,
     ) -> Result<Self>
     where
@@ -89,13 +464,63 @@ where
             .ends_with(name.as_str()));
 
         {
+            let mut versions = lock.wait(&txn_id, versions.write()).await?;
+
             let size = version.get_size();
-            let mut versions = versions.write().await;
-            versions.create_file(txn_id.to_string(), version, size)?;
+            lock.reserve(txn_id, size)?;
+
+            if let Err(cause) = versions.create_file(txn_id.to_string(), version, size) {
+                lock.free(txn_id);
+                return Err(cause.into());
+            }
+        }
+
+        Ok(Self {
+            last_modified: TxnLock::new(txn_id),
+            validation: Self::validation(&lock, txn_id),
+            lazy: None,
+            tracker: Tracker::unknown(),
+            canonical: Arc::new(Mutex::new(None)),
+            lock,
+            versions,
+            parent,
+            name: Arc::new(name),
+        })
+    }
+
+    pub(super) fn try_create<F>(
+        txn_id: TxnId,
+        name: Id,
+        parent: DirLock<FE>,
+        versions: DirLock<FE>,
+        lock: LockContext<TxnId>,
+        version: F,
+    ) -> Result<Self>
+    where
+        FE: AsType<F>,
+        F: GetSize,
+    {
+        {
+            let mut versions = versions
+                .try_write()
+                .map_err(|cause| lock.io_error(&txn_id, cause))?;
+
+            let size = version.get_size();
+            lock.reserve(txn_id, size)?;
+
+            if let Err(cause) = versions.create_file(txn_id.to_string(), version, size) {
+                lock.free(txn_id);
+                return Err(cause.into());
+            }
         }
 
         Ok(Self {
             last_modified: TxnLock::new(txn_id),
+            validation: Self::validation(&lock, txn_id),
+            lazy: None,
+            tracker: Tracker::unknown(),
+            canonical: Arc::new(Mutex::new(None)),
+            lock,
             versions,
             parent,
             name: Arc::new(name),
@@ -107,30 +532,34 @@ where
         name: Id,
         parent: DirLock<FE>,
         versions: DirLock<FE>,
+        lock: LockContext<TxnId>,
+        lazy: bool,
     ) -> Result<Self> {
         #[cfg(feature = "logging")]
         log::debug!("load file {} into the transactional filesystem cache", name);
 
-        debug_assert!(versions
-            .try_read()
-            .expect("version dir")
-            .path()
-            .to_str()
-            .expect("path")
-            .ends_with(name.as_str()));
+        debug_assert!(
+            lock.is_read_only()
+                || versions
+                    .try_read()
+                    .expect("version dir")
+                    .path()
+                    .to_str()
+                    .expect("path")
+                    .ends_with(name.as_str())
+        );
 
-        {
+        // a lazily loaded file is verified on first access instead
+        if lock.checksums() && (!lazy || lock.is_read_only()) {
+            verify(&lock, &txn_id).await?;
+        }
+
+        if !lock.is_read_only() {
             let parent = parent.try_read().map_err(Error::from)?;
 
-            let canon = parent.get_file(&name).ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!(
-                        "cannot load the transactional file {} without a canonical version",
-                        name
-                    ),
-                )
-            })?;
+            let canon = parent
+                .get_file(&name)
+                .ok_or_else(|| Error::NotFound(lock.location(&txn_id)))?;
 
             #[cfg(feature = "logging")]
             log::trace!("acquiring write lock on versions dir for file {name}...");
@@ -141,38 +570,203 @@ where
             log::trace!("truncate obsolete versions of {name}...");
             versions.truncate().await;
 
-            versions.copy_file_from(txn_id.to_string(), &canon).await?;
+            if !lazy {
+                versions.copy_file_from(txn_id.to_string(), canon).await?;
 
-            #[cfg(feature = "logging")]
-            log::trace!("copied canonical version of {:?}", canon);
+                #[cfg(feature = "logging")]
+                log::trace!("copied canonical version of {:?}", canon);
+            }
         }
 
+        let tracker = Tracker::load(lock.path()).await?;
+
+        let lazy = if lazy && !lock.is_read_only() {
+            Some(Arc::new(futures::lock::Mutex::new(Some(txn_id))))
+        } else {
+            None
+        };
+
         Ok(Self {
             last_modified: TxnLock::new(txn_id),
+            validation: Self::validation(&lock, txn_id),
+            lazy,
+            tracker,
+            canonical: Arc::new(Mutex::new(Some(txn_id))),
+            lock,
             versions,
             parent,
             name: Arc::new(name),
         })
     }
+
+    fn validation(
+        lock: &LockContext<TxnId>,
+        txn_id: TxnId,
+    ) -> Option<Arc<Mutex<Validation<TxnId>>>> {
+        // a read-only file is never written, so there's nothing to validate
+        if lock.is_optimistic() && !lock.is_read_only() {
+            Some(Arc::new(Mutex::new(Validation::new(txn_id))))
+        } else {
+            None
+        }
+    }
+
+    // read the version of this file with the given ID at `txn_id`,
+    // or its canonical version if read-only
+    async fn read_version<F>(
+        &self,
+        txn_id: TxnId,
+        version_id: &TxnId,
+    ) -> Result<FileReadGuardOwned<FE, F>>
+    where
+        F: FileLoad,
+        FE: AsType<F>,
+    {
+        let read = async {
+            if self.lock.is_read_only() {
+                let parent = self.parent.read().await;
+                parent.read_file_owned(&*self.name).await
+            } else {
+                let versions = self.versions.read().await;
+                versions.read_file_owned(version_id).await
+            }
+        };
+
+        self.lock.wait(&txn_id, read).await?.map_err(Error::from)
+    }
+
+    // read the version of this file with the given ID synchronously, if possible
+    fn try_read_version<F>(&self, version_id: &TxnId) -> io::Result<FileReadGuardOwned<FE, F>>
+    where
+        F: FileLoad,
+        FE: AsType<F>,
+    {
+        if self.lock.is_read_only() {
+            let parent = self.parent.try_read()?;
+            let canon = parent.get_file(&*self.name).expect("canonical version");
+            canon.try_read_owned()
+        } else {
+            let versions = self.versions.try_read()?;
+            let version = versions.get_file(version_id).expect("version");
+            version.try_read_owned()
+        }
+    }
+
+    // if this file was loaded lazily, copy its canonical version into its versions directory
+    // the first time it's accessed
+    async fn load_canon(&self, txn_id: &TxnId) -> Result<()> {
+        if let Some(lazy) = &self.lazy {
+            let mut pending = self.lock.wait(txn_id, lazy.lock()).await?;
+
+            if let Some(version_id) = &*pending {
+                if self.lock.checksums() {
+                    verify(&self.lock, txn_id).await?;
+                }
+
+                let parent = self.lock.wait(txn_id, self.parent.read()).await?;
+
+                let canon = parent
+                    .get_file(&*self.name)
+                    .ok_or_else(|| Error::NotFound(self.lock.location(txn_id)))?;
+
+                let mut versions = self.lock.wait(txn_id, self.versions.write()).await?;
+                versions
+                    .copy_file_from(version_id.to_string(), canon)
+                    .await?;
+
+                *pending = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    // fail with `Error::WouldBlock` if this file was loaded lazily and hasn't been accessed yet
+    fn try_load_canon(&self, txn_id: &TxnId) -> Result<()> {
+        if let Some(lazy) = &self.lazy {
+            let loaded = lazy.try_lock().is_some_and(|pending| pending.is_none());
+
+            if !loaded {
+                return Err(Error::WouldBlock(self.lock.location(txn_id)));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl<TxnId, FE> File<TxnId, FE>
 where
     TxnId: Name + fmt::Display + fmt::Debug + Hash + Ord + Copy,
-    FE: Send + Sync,
+    FE: Clone + Send + Sync,
 {
     /// Lock this file for reading at the given `txn_id`.
+    ///
+    /// In optimistic mode this does not take any transactional lock: the read is validated when
+    /// `txn_id` is committed instead. Otherwise, the returned guard only holds a read lock,
+    /// so it can't be upgraded (see [`FileVersionRead::upgrade`]): call [`File::read_upgradable`]
+    /// instead to read a version which may then be written.
     pub async fn read<F>(&self, txn_id: TxnId) -> Result<FileVersionRead<TxnId, FE, F>>
     where
         F: FileLoad,
         FE: AsType<F>,
     {
-        let last_modified = self.last_modified.read(txn_id).await?;
-        let versions = self.versions.read().await;
-        let version = versions.read_file_owned(&*last_modified).await?;
+        self.load_canon(&txn_id).await?;
+
+        if let Some(validation) = &self.validation {
+            let (version_id, _written) = self.observe(validation, txn_id, true)?;
+            let version = self.read_version(txn_id, &version_id).await?;
+
+            return Ok(FileVersionRead {
+                file: self.clone(),
+                txn_id,
+                _permit: Permit::Snapshot,
+                version,
+            });
+        }
+
+        let last_modified = self
+            .lock
+            .acquire(txn_id, self.last_modified.read(txn_id))
+            .await?;
+        let version = self.read_version(txn_id, &*last_modified).await?;
 
         Ok(FileVersionRead {
-            _modified: last_modified,
+            file: self.clone(),
+            txn_id,
+            _permit: Permit::Read(last_modified),
+            version,
+        })
+    }
+
+    /// Lock this file for reading at the given `txn_id` synchronously, if possible.
+    pub fn try_read<F>(&self, txn_id: TxnId) -> Result<FileVersionRead<TxnId, FE, F>>
+    where
+        F: FileLoad,
+        FE: AsType<F>,
+    {
+        self.try_load_canon(&txn_id)?;
+
+        let (version_id, permit) = if let Some(validation) = &self.validation {
+            let (version_id, _written) = self.observe(validation, txn_id, true)?;
+            (version_id, Permit::Snapshot)
+        } else {
+            let last_modified = self
+                .last_modified
+                .try_read(txn_id)
+                .map_err(|cause| self.lock.error(&txn_id, cause))?;
+
+            (*last_modified, Permit::Read(last_modified))
+        };
+
+        let version = self
+            .try_read_version(&version_id)
+            .map_err(|cause| self.lock.io_error(&txn_id, cause))?;
+
+        Ok(FileVersionRead {
+            file: self.clone(),
+            txn_id,
+            _permit: permit,
             version,
         })
     }
@@ -186,96 +780,774 @@ where
         self.read(txn_id).await
     }
 
+    /// Lock this file for reading at the given `txn_id` with a guard which can be upgraded
+    /// with [`FileVersionRead::upgrade`] without releasing its lock in between.
+    ///
+    /// Unlike [`File::read`], this takes the write lock on this file at `txn_id` up front,
+    /// so it blocks other readers and writers just like [`File::write`] would, but the current
+    /// version is only copied if the guard is upgraded. In optimistic mode this is the same as
+    /// [`File::read`], since any read guard can be upgraded.
+    pub async fn read_upgradable<F>(&self, txn_id: TxnId) -> Result<FileVersionRead<TxnId, FE, F>>
+    where
+        F: FileLoad,
+        FE: AsType<F>,
+    {
+        if self.validation.is_some() {
+            return self.read(txn_id).await;
+        }
+
+        self.lock.check_writable(&txn_id)?;
+        self.load_canon(&txn_id).await?;
+
+        let last_modified = self
+            .lock
+            .acquire(txn_id, self.last_modified.write(txn_id))
+            .await?;
+
+        if *last_modified > txn_id {
+            return Err(Error::Outdated(self.lock.location(&txn_id)));
+        }
+
+        let version = self.read_version(txn_id, &*last_modified).await?;
+
+        Ok(FileVersionRead {
+            file: self.clone(),
+            txn_id,
+            _permit: Permit::Upgradable(last_modified),
+            version,
+        })
+    }
+
     /// Lock this file for writing at the given `txn_id`.
+    ///
+    /// In optimistic mode this does not take any transactional lock: the new version is private
+    /// to `txn_id` until it's committed, and the commit fails with [`Error::Conflict`] if another
+    /// transaction committed a new version of this file in the meantime. Tasks in the same
+    /// transaction should not write the same file concurrently in optimistic mode.
     pub async fn write<F>(&self, txn_id: TxnId) -> Result<FileVersionWrite<TxnId, FE, F>>
     where
+        TxnId: Send + Sync + 'static,
         F: FileLoad + Clone + GetSize,
-        FE: AsType<F>,
+        FE: AsType<F> + 'static,
     {
-        let mut last_modified = self.last_modified.write(txn_id).await?;
-        let mut versions = self.versions.write().await;
+        self.lock.check_writable(&txn_id)?;
+        self.load_canon(&txn_id).await?;
 
-        let version = if last_modified < txn_id {
-            let canon = versions.read_file_owned(&*last_modified).await?;
-            *last_modified = txn_id;
+        if let Some(validation) = &self.validation {
+            let (version_id, written) = self.observe(validation, txn_id, true)?;
+            let mut versions = self.lock.wait(&txn_id, self.versions.write()).await?;
+
+            let (version, charge, prior) = if written {
+                let charge = self.lock.charge(txn_id)?;
+                let version = versions.get_file(&version_id).expect("version").clone();
+                (version, charge, None)
+            } else {
+                let canon = versions.read_file_owned(&version_id);
+                let canon = self.lock.wait(&txn_id, canon).await??;
+                let version = F::clone(&*canon);
+                let size = version.get_size();
+                let charge = self.lock.reserve(txn_id, size)?;
+
+                // this will replace any unmodified version left behind by an earlier write guard
+                let version = versions.create_file(txn_id.to_string(), version, size)?;
+                (version, Some(charge), Some(version_id))
+            };
+
+            return Ok(FileVersionWrite {
+                file: self.clone(),
+                txn_id,
+                version: VersionWriteGuard::new(
+                    self.lock.wait(&txn_id, version.write_owned()).await??,
+                    charge,
+                ),
+                modified: self.modified(txn_id, None, prior),
+                lock: version,
+            });
+        }
+
+        let last_modified = self
+            .lock
+            .acquire(txn_id, self.last_modified.write(txn_id))
+            .await?;
+
+        self.write_locked(txn_id, last_modified).await
+    }
+
+    // lock the version of this file at `txn_id` for writing, creating it if needed,
+    // given the write lock on its last-modified version ID at `txn_id`
+    async fn write_locked<F>(
+        &self,
+        txn_id: TxnId,
+        mut last_modified: TxnLockWriteGuard<TxnId>,
+    ) -> Result<FileVersionWrite<TxnId, FE, F>>
+    where
+        TxnId: Send + Sync + 'static,
+        F: FileLoad + Clone + GetSize,
+        FE: AsType<F> + 'static,
+    {
+        let mut versions = self.lock.wait(&txn_id, self.versions.write()).await?;
+
+        let (version, charge, prior) = if last_modified < txn_id {
+            let prior = *last_modified;
+            let canon = versions.read_file_owned(&*last_modified);
+            let canon = self.lock.wait(&txn_id, canon).await??;
 
             let version = F::clone(&*canon);
             let size = version.get_size();
+            let charge = self.lock.reserve(txn_id, size)?;
+
+            // this will replace any unmodified version left behind by an earlier write guard
+            let version = versions.create_file(txn_id.to_string(), version, size)?;
+            *last_modified = txn_id;
 
-            versions.create_file(txn_id.to_string(), version, size)?
+            (version, Some(charge), Some(prior))
         } else if last_modified == txn_id {
-            versions.get_file(&*last_modified).expect("version").clone()
+            let charge = self.lock.charge(txn_id)?;
+            let version = versions.get_file(&*last_modified).expect("version").clone();
+            (version, charge, None)
         } else {
-            return Err(txn_lock::Error::Outdated.into());
+            return Err(Error::Outdated(self.lock.location(&txn_id)));
         };
 
         Ok(FileVersionWrite {
-            _modified: last_modified,
-            version: version.write_owned().await?,
+            file: self.clone(),
+            txn_id,
+            version: VersionWriteGuard::new(
+                self.lock.wait(&txn_id, version.write_owned()).await??,
+                charge,
+            ),
+            modified: self.modified(txn_id, Some(last_modified), prior),
+            lock: version,
+        })
+    }
+
+    /// Lock this file for writing at the given `txn_id` synchronously, if possible.
+    pub fn try_write<F>(&self, txn_id: TxnId) -> Result<FileVersionWrite<TxnId, FE, F>>
+    where
+        TxnId: Send + Sync + 'static,
+        F: FileLoad + Clone + GetSize,
+        FE: AsType<F> + 'static,
+    {
+        self.lock.check_writable(&txn_id)?;
+        self.try_load_canon(&txn_id)?;
+
+        let (guard, prior) = if let Some(validation) = &self.validation {
+            let (version_id, written) = self.observe(validation, txn_id, true)?;
+            (None, if written { None } else { Some(version_id) })
+        } else {
+            let last_modified = self
+                .last_modified
+                .try_write(txn_id)
+                .map_err(|cause| self.lock.error(&txn_id, cause))?;
+
+            if *last_modified < txn_id {
+                let prior = *last_modified;
+                (Some(last_modified), Some(prior))
+            } else if *last_modified == txn_id {
+                (Some(last_modified), None)
+            } else {
+                return Err(Error::Outdated(self.lock.location(&txn_id)));
+            }
+        };
+
+        let mut versions = self
+            .versions
+            .try_write()
+            .map_err(|cause| self.lock.io_error(&txn_id, cause))?;
+
+        let (guard, version, charge) = if let Some(prior) = prior {
+            let canon = versions
+                .get_file(&prior)
+                .expect("version")
+                .try_read_owned()
+                .map_err(|cause| self.lock.io_error(&txn_id, cause))?;
+
+            let version = F::clone(&*canon);
+            let size = version.get_size();
+            let charge = self.lock.reserve(txn_id, size)?;
+
+            // this will replace any unmodified version left behind by an earlier write guard
+            let version = versions.create_file(txn_id.to_string(), version, size)?;
+
+            let guard = guard.map(|mut last_modified| {
+                *last_modified = txn_id;
+                last_modified
+            });
+
+            (guard, version, Some(charge))
+        } else {
+            let charge = self.lock.charge(txn_id)?;
+            let version = versions.get_file(&txn_id).expect("version").clone();
+            (guard, version, charge)
+        };
+
+        let contents = version
+            .try_write_owned()
+            .map_err(|cause| self.lock.io_error(&txn_id, cause))?;
+
+        Ok(FileVersionWrite {
+            file: self.clone(),
+            txn_id,
+            version: VersionWriteGuard::new(contents, charge),
+            modified: self.modified(txn_id, guard, prior),
+            lock: version,
         })
     }
 
     /// Lock this file for writing at the given `txn_id` without borrowing.
     pub async fn into_write<F>(self, txn_id: TxnId) -> Result<FileVersionWrite<TxnId, FE, F>>
     where
+        TxnId: Send + Sync + 'static,
         F: FileLoad + Clone + GetSize,
-        FE: AsType<F>,
+        FE: AsType<F> + 'static,
     {
         self.write(txn_id).await
     }
+
+    /// Replace the contents of this file at the given `txn_id` with `contents`,
+    /// without reading or cloning its current version.
+    pub async fn overwrite<F>(&self, txn_id: TxnId, contents: F) -> Result<()>
+    where
+        F: GetSize,
+        FE: AsType<F>,
+    {
+        self.lock.check_writable(&txn_id)?;
+        self.load_canon(&txn_id).await?;
+
+        let last_modified = if let Some(validation) = &self.validation {
+            self.observe(validation, txn_id, false)?;
+            None
+        } else {
+            let last_modified = self
+                .lock
+                .acquire(txn_id, self.last_modified.write(txn_id))
+                .await?;
+
+            if *last_modified > txn_id {
+                return Err(Error::Outdated(self.lock.location(&txn_id)));
+            }
+
+            Some(last_modified)
+        };
+
+        {
+            let name = txn_id.to_string();
+            let size = contents.get_size();
+            let mut versions = self.lock.wait(&txn_id, self.versions.write()).await?;
+            self.lock.reserve(txn_id, size)?;
+
+            // replace any version already written at this transaction
+            versions.delete(&name).await;
+
+            if let Err(cause) = versions.create_file(name, contents, size) {
+                self.lock.free(txn_id);
+                return Err(cause.into());
+            }
+        }
+
+        if let Some(mut last_modified) = last_modified {
+            *last_modified = txn_id;
+        } else {
+            self.mark_written(&txn_id);
+        }
+
+        Ok(())
+    }
+
+    /// Replace the version of this file at `txn_id` with the contents of its canonical version
+    /// on the host filesystem, e.g. to accept an [`crate::ExternalChange`].
+    /// Committing `txn_id` then makes these contents the canonical version.
+    ///
+    /// This fails with [`Error::NotFound`] if the canonical version was deleted.
+    pub async fn reload<F>(&self, txn_id: TxnId) -> Result<()>
+    where
+        F: FileLoad + GetSize,
+        FE: AsType<F>,
+    {
+        self.lock.check_writable(&txn_id)?;
+
+        let path = self.lock.path();
+
+        let file = match fs::File::open(path).await {
+            Ok(file) => file,
+            Err(cause) if cause.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::NotFound(self.lock.location(&txn_id)));
+            }
+            Err(cause) => return Err(cause.into()),
+        };
+
+        let metadata = file.metadata().await?;
+        let contents = F::load(path, file, metadata).await?;
+
+        self.overwrite(txn_id, contents).await
+    }
+
+    /// Write the version of this file visible at `txn_id` again, so that committing `txn_id`
+    /// reverts an [`crate::ExternalChange`] to its canonical version on the host filesystem.
+    pub async fn restore<F>(&self, txn_id: TxnId) -> Result<()>
+    where
+        F: FileLoad + Clone + GetSize,
+        FE: AsType<F>,
+    {
+        self.lock.check_writable(&txn_id)?;
+
+        let contents = F::clone(&*self.read(txn_id).await?);
+        self.overwrite(txn_id, contents).await
+    }
+
+    // in optimistic mode, return the ID of the version of this file visible at `txn_id`
+    // and whether it was written at `txn_id`, recording the version observed by `txn_id`
+    // and, if `read` is `true`, that `txn_id` has read this file
+    fn observe(
+        &self,
+        validation: &Mutex<Validation<TxnId>>,
+        txn_id: TxnId,
+        read: bool,
+    ) -> Result<(TxnId, bool)> {
+        let mut validation = validation.lock().expect("file validation");
+        let committed = validation.committed;
+
+        let observed = if let Some(access) = validation.pending.get(&txn_id) {
+            if access.written {
+                (txn_id, true)
+            } else {
+                (access.observed, false)
+            }
+        } else if committed > txn_id {
+            return Err(Error::Outdated(self.lock.location(&txn_id)));
+        } else if committed == txn_id {
+            // this file was created or loaded at `txn_id`
+            (txn_id, true)
+        } else {
+            let access = Access {
+                observed: committed,
+                superseded: None,
+                written: false,
+            };
+
+            validation.pending.insert(txn_id, access);
+            (committed, false)
+        };
+
+        if read && txn_id > validation.last_read {
+            validation.last_read = txn_id;
+        }
+
+        Ok(observed)
+    }
+}
+
+impl<TxnId: Hash + Eq, FE> File<TxnId, FE> {
+    // in optimistic mode, record that a version of this file was written at `txn_id`
+    fn mark_written(&self, txn_id: &TxnId) {
+        if let Some(validation) = &self.validation {
+            let mut validation = validation.lock().expect("file validation");
+            validation.mark_written(txn_id);
+        }
+    }
+}
+
+impl<TxnId, FE> File<TxnId, FE>
+where
+    TxnId: Name + fmt::Display + fmt::Debug + Hash + Ord + Copy,
+    FE: for<'a> FileSave<'a> + Clone + Send + Sync,
+{
+    /// Open a streaming reader over the version of this file at the given `txn_id`.
+    ///
+    /// The reader reads the version's encoded contents as stored on the host filesystem.
+    ///
+    /// This holds a read lock on this file at `txn_id` until the reader is dropped.
+    pub async fn reader(&self, txn_id: TxnId) -> Result<FileVersionReader<TxnId>> {
+        let (version_id, last_modified) = self.read_permit(txn_id).await?;
+        let path = self.sync_version(txn_id, &version_id).await?;
+        let file = fs::File::open(path).await?;
+
+        Ok(FileVersionReader {
+            _modified: last_modified,
+            file,
+        })
+    }
+
+    /// Read the given byte `range` of the version of this file at the given `txn_id`
+    /// directly from the host filesystem, without loading it into the cache.
+    ///
+    /// Unlike [`File::reader`], this never writes to the host filesystem, so it only reads
+    /// the canonical version of this file. It fails with [`Error::WouldBlock`] if the version
+    /// at `txn_id` is not the canonical version, or its commit has not been synchronized yet,
+    /// e.g. if it was written at `txn_id`.
+    ///
+    /// The result will be shorter than the `range` if the end of the file is reached.
+    pub async fn read_range(&self, txn_id: TxnId, range: Range<u64>) -> Result<Vec<u8>> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+
+        let (version_id, _last_modified) = self.read_permit(txn_id).await?;
+
+        // a lazily loaded file is verified on first access, but reading it here doesn't load it
+        if let Some(lazy) = &self.lazy {
+            let pending = self.lock.wait(&txn_id, lazy.lock()).await?;
+
+            if pending.is_some() && self.lock.checksums() {
+                verify(&self.lock, &txn_id).await?;
+            }
+        }
+
+        // hold a read lock on the canonical dir until the read is done,
+        // so that a commit can't overwrite the canonical version in the meantime
+        let parent = self.lock.wait(&txn_id, self.parent.read()).await?;
+        let path = parent.path().join(self.name.as_str());
+
+        let canonical = self.lock.is_read_only()
+            || (*self.canonical.lock().expect("canonical version") == Some(version_id)
+                && parent.get_file(&*self.name).is_some()
+                && self.tracker.is_current(&path).await?);
+
+        if !canonical {
+            return Err(Error::WouldBlock(self.lock.location(&txn_id)));
+        }
+
+        let mut file = fs::File::open(path).await?;
+
+        // the range may extend past the end of the file, so don't trust it to size the buffer
+        let size = file.metadata().await?.len();
+        let len = Ord::min(range.end, size).saturating_sub(range.start);
+        let mut buffer = Vec::with_capacity(len as usize);
+
+        if len == 0 {
+            return Ok(buffer);
+        }
+
+        file.seek(SeekFrom::Start(range.start)).await?;
+        (&mut file).take(len).read_to_end(&mut buffer).await?;
+
+        std::mem::drop(parent);
+
+        Ok(buffer)
+    }
+
+    /// Open a streaming writer over a new version of this file at the given `txn_id`.
+    ///
+    /// The writer starts with the current contents of this file (call
+    /// [`FileVersionWriter::set_len`] to shorten them) and holds a write lock on this file
+    /// at `txn_id` until it's dropped. Call [`FileVersionWriter::finish`] to keep the changes.
+    pub async fn writer(&self, txn_id: TxnId) -> Result<FileVersionWriter<TxnId, FE>> {
+        self.lock.check_writable(&txn_id)?;
+
+        let (version_id, last_modified) = if let Some(validation) = &self.validation {
+            let (version_id, _written) = self.observe(validation, txn_id, true)?;
+            (version_id, None)
+        } else {
+            let last_modified = self
+                .lock
+                .acquire(txn_id, self.last_modified.write(txn_id))
+                .await?;
+
+            if *last_modified > txn_id {
+                return Err(Error::Outdated(self.lock.location(&txn_id)));
+            }
+
+            (*last_modified, Some(last_modified))
+        };
+
+        // stage the new version in a hidden dir which the cache never lists: freqfs only lists
+        // a dir on disk when the cache is loaded, and loading the root dir clears the dir which
+        // contains the staging dir, along with any file left in it (e.g. by a crash)
+        let source = self.sync_version(txn_id, &version_id).await?;
+        let staging_dir = self.lock.staging_dir();
+        fs::create_dir_all(staging_dir).await?;
+
+        let staged = NEXT_STAGED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = staging_dir.join(format!("{txn_id}.{staged}.partial"));
+        fs::copy(&source, &path).await?;
+
+        let file = fs::OpenOptions::new().write(true).open(&path).await?;
+
+        Ok(FileVersionWriter {
+            modified: last_modified,
+            lock: self.lock.clone(),
+            validation: self.validation.clone(),
+            txn_id,
+            versions: self.versions.clone(),
+            path,
+            file,
+        })
+    }
+
+    // lock this file for reading at `txn_id` and return the ID of the version to read,
+    // with the lock on its last-modified version ID in pessimistic mode
+    async fn read_permit(&self, txn_id: TxnId) -> Result<(TxnId, Option<TxnLockReadGuard<TxnId>>)> {
+        if let Some(validation) = &self.validation {
+            let (version_id, _written) = self.observe(validation, txn_id, true)?;
+            Ok((version_id, None))
+        } else {
+            let last_modified = self
+                .lock
+                .acquire(txn_id, self.last_modified.read(txn_id))
+                .await?;
+
+            Ok((*last_modified, Some(last_modified)))
+        }
+    }
+
+    // make sure that the given version is up-to-date on the host filesystem and return its path
+    async fn sync_version(&self, txn_id: TxnId, version_id: &TxnId) -> Result<PathBuf> {
+        if self.lock.is_read_only() {
+            // the canonical version is never modified, so it's already up-to-date
+            let parent = self.lock.wait(&txn_id, self.parent.read()).await?;
+            return Ok(parent.path().join(self.name.as_str()));
+        }
+
+        self.load_canon(&txn_id).await?;
+
+        let versions = self.lock.wait(&txn_id, self.versions.read()).await?;
+        let version = versions.get_file(version_id).expect("version");
+        self.lock.wait(&txn_id, version.sync()).await??;
+        Ok(version.path().to_path_buf())
+    }
 }
 
 impl<TxnId, FE> File<TxnId, FE>
 where
-    TxnId: Name + Hash + Ord + PartialOrd<str> + fmt::Debug + Copy + Send + Sync,
+    TxnId: Name + Hash + Ord + PartialOrd<str> + fmt::Display + fmt::Debug + Copy + Send + Sync,
     FE: for<'a> FileSave<'a> + Send + Sync,
 {
     /// Commit the state of this file at `txn_id`.
     /// This will un-block any pending future write locks.
     /// If this file was modified at `txn_id`, it will replace the canonical version with
-    /// the modified version and sync with the host filesystem.
-    pub async fn commit(&self, txn_id: TxnId)
+    /// the modified version and sync with the host filesystem according to the default
+    /// [`Durability`] of its [`crate::Dir`] (together with any other commits in its group,
+    /// if group commit is enabled; see [`crate::DirOptions::group_commit`]).
+    /// Otherwise, this will not perform any I/O.
+    ///
+    /// In optimistic mode, this fails with [`Error::Conflict`] if another transaction committed
+    /// a new version of this file after `txn_id` first read or wrote it,
+    /// in which case `txn_id` should be rolled back. As with [`crate::Dir::commit`],
+    /// an [`Error::IO`] means that `txn_id` was committed but not synchronized.
+    pub async fn commit(&self, txn_id: TxnId) -> Result<()>
+    where
+        FE: Clone,
+    {
+        self.commit_with(txn_id, self.lock.durability()).await
+    }
+
+    /// Commit the state of this file at `txn_id` with the given `durability`.
+    /// See [`File::commit`] for details.
+    pub async fn commit_with(&self, txn_id: TxnId, durability: Durability) -> Result<()>
     where
         FE: Clone,
     {
-        let last_modified = self.last_modified.read_and_commit(txn_id).await;
+        if self.lock.is_read_only() {
+            // a read-only transaction has no changes to validate or synchronize
+            self.commit_inner(txn_id).await;
+            return Ok(());
+        }
 
-        if &*last_modified == &txn_id {
-            let versions = self.versions.read().await;
-            if let DirEntry::File(file) = versions.get(&txn_id).expect("version") {
+        let syncs = Syncs::default();
+
+        {
+            let _permit = self.lock.wait(&txn_id, self.lock.commit_permit()).await?;
+            self.validate(txn_id)?;
+
+            if let Some(version) = self.written_version(txn_id).await {
                 let mut parent = self.parent.write().await;
+                self.copy_to_canon(txn_id, &version, &mut parent, &syncs)
+                    .await;
+            }
 
-                let canon = parent
-                    .copy_file_from(self.name.to_string(), file)
-                    .await
-                    .expect("copy canonical version");
-
-                canon
-                    .sync()
-                    .await
-                    .expect("sync canonical version with the filesystem");
-            } else {
-                unreachable!("transactional file out of sync with filesystem");
+            self.commit_inner(txn_id).await;
+        }
+
+        self.lock.sync(syncs, durability).await.map_err(Error::from)
+    }
+
+    // copy the `version` of this file written at `txn_id` into its locked `canon` dir
+    // and add the new canonical version to `syncs`
+    pub(super) async fn copy_to_canon(
+        &self,
+        txn_id: TxnId,
+        version: &FileLock<FE>,
+        canon: &mut freqfs::Dir<FE>,
+        syncs: &Syncs,
+    ) where
+        FE: Clone,
+    {
+        if self.lock.checksums() {
+            syncs.invalidate(self.lock.path()).await;
+        }
+
+        let file = canon
+            .copy_file_from(self.name.to_string(), version)
+            .await
+            .expect("copy canonical version");
+
+        *self.canonical.lock().expect("canonical version") = Some(txn_id);
+        syncs.file(file, Some(&self.tracker), self.lock.checksums());
+    }
+
+    // in optimistic mode, check that committing `txn_id` won't break serializability:
+    // no new version of this file visible at `txn_id` can have been committed since `txn_id`
+    // first observed it, and if it was written at `txn_id`, no other version can have been
+    // committed since and no later transaction can have read it
+    pub(super) fn validate(&self, txn_id: TxnId) -> Result<()> {
+        if let Some(validation) = &self.validation {
+            let validation = validation.lock().expect("file validation");
+
+            if let Some(access) = validation.pending.get(&txn_id) {
+                let conflict = if access.written {
+                    access.superseded.is_some() || validation.last_read > txn_id
+                } else {
+                    // a version committed after `txn_id` would not have been visible anyway
+                    access
+                        .superseded
+                        .is_some_and(|superseded| superseded < txn_id)
+                };
+
+                if conflict {
+                    return Err(Error::Conflict(self.lock.location(&txn_id)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // return the version of this file written at `txn_id`, if any, which has to be copied into
+    // the canonical dir before `txn_id` is committed, since committing it releases this file
+    // to a later transaction which could otherwise install a newer version first
+    pub(super) async fn written_version(&self, txn_id: TxnId) -> Option<FileLock<FE>> {
+        if self.lock.is_read_only() {
+            // the only version is the canonical version
+            return None;
+        }
+
+        let written = if let Some(validation) = &self.validation {
+            let validation = validation.lock().expect("file validation");
+
+            validation
+                .pending
+                .get(&txn_id)
+                .is_some_and(|access| access.written)
+        } else {
+            let last_modified = self
+                .last_modified
+                .read(txn_id)
+                .await
+                .expect("last modified");
+            *last_modified == txn_id
+        };
+
+        if !written {
+            return None;
+        }
+
+        let versions = self.versions.read().await;
+        match versions.get(&txn_id) {
+            Some(DirEntry::File(file)) => Some(file.clone()),
+            // a lazily loaded file whose canonical version was never accessed
+            None if self.lazy.is_some() => None,
+            _ => unreachable!("transactional file out of sync with filesystem"),
+        }
+    }
+
+    // commit the state of this file at `txn_id`, which must already be validated,
+    // and whose new version, if any, must already have been copied into the canonical dir
+    pub(super) async fn commit_inner(&self, txn_id: TxnId) {
+        let modified = if let Some(validation) = &self.validation {
+            let mut validation = validation.lock().expect("file validation");
+
+            if let Some(access) = validation.pending.remove(&txn_id) {
+                if access.written {
+                    validation.committed = txn_id;
+
+                    for access in validation.pending.values_mut() {
+                        access.superseded.get_or_insert(txn_id);
+                    }
+                }
             }
+
+            validation.committed == txn_id
+        } else {
+            let last_modified = self.last_modified.read_and_commit(txn_id).await;
+            *last_modified == txn_id
+        };
+        self.lock.free(txn_id);
+
+        if !modified && !self.lock.is_read_only() {
+            self.discard_unmodified(txn_id).await;
         }
     }
 
     pub async fn rollback(&self, txn_id: TxnId) {
-        let last_modified = self.last_modified.read_and_rollback(txn_id).await;
+        let modified = if let Some(validation) = &self.validation {
+            let mut validation = validation.lock().expect("file validation");
+            validation.pending.remove(&txn_id);
+            validation.committed == txn_id
+        } else {
+            let last_modified = self.last_modified.read_and_rollback(txn_id).await;
+            *last_modified == txn_id
+        };
+        self.lock.free(txn_id);
+
+        if self.lock.is_read_only() {
+            // there are no versions to discard
+            return;
+        }
 
-        if &*last_modified == &txn_id {
+        if modified {
             let mut versions = self.versions.write().await;
             versions.delete(&txn_id).await;
+        } else {
+            self.discard_unmodified(txn_id).await;
         }
     }
 
     pub async fn finalize(&self, txn_id: TxnId) {
-        if let Some(last_modified) = self.last_modified.read_and_finalize(txn_id) {
+        if let Some(validation) = &self.validation {
             let mut versions = self.versions.write().await;
 
+            let to_delete = {
+                let validation = validation.lock().expect("file validation");
+
+                // keep the committed version and any version observed or written by a pending txn
+                let in_use = |version_id: &str| {
+                    validation.committed == *version_id
+                        || validation.pending.iter().any(|(pending_id, access)| {
+                            *pending_id == *version_id || access.observed == *version_id
+                        })
+                };
+
+                versions
+                    .names()
+                    .filter(|version_id| txn_id > *version_id.as_str())
+                    .filter(|version_id| !in_use(version_id))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+
+            for version_id in to_delete {
+                versions.delete(&version_id).await;
+            }
+        } else if let Some(last_modified) = self.last_modified.read_and_finalize(txn_id) {
+            if self.lock.is_read_only() {
+                // there are no obsolete versions to delete
+                return;
+            }
+
+            let mut versions = self.versions.write().await;
+
+            // the last-modified version is still the current version, so it must be kept,
+            // but any other version up to `txn_id` was left behind by a write guard which never
+            // modified it, in a transaction which was never committed or rolled back
             let to_delete = versions
                 .names()
-                .filter(|version_id| *last_modified >= *version_id.as_str())
+                .filter(|version_id| {
+                    *last_modified > *version_id.as_str()
+                        || (txn_id >= *version_id.as_str()
+                            && *last_modified != *version_id.as_str())
+                })
                 .cloned()
                 .collect::<Vec<_>>();
 
@@ -286,6 +1558,68 @@ where
     }
 }
 
+impl<TxnId, FE> File<TxnId, FE>
+where
+    TxnId: Name + Send + Sync,
+    FE: Send + Sync,
+{
+    // drop a version created at `txn_id` by a write guard which never modified it
+    async fn discard_unmodified(&self, txn_id: TxnId) {
+        let exists = self.versions.read().await.get(&txn_id).is_some();
+
+        if exists {
+            let mut versions = self.versions.write().await;
+            versions.delete(&txn_id).await;
+        }
+    }
+}
+
+impl<TxnId, FE> File<TxnId, FE>
+where
+    TxnId: Name + Hash + Ord + Copy + Send + Sync + 'static,
+    FE: Send + Sync + 'static,
+{
+    // construct the write permit of a new write guard at `txn_id`, which releases the memory
+    // charged for the version created from the `prior` version, if any, when it's dropped
+    // without being modified; the version itself is deleted from the cache when `txn_id` is
+    // committed, rolled back or finalized, or replaced by the next write guard at `txn_id`
+    fn modified(
+        &self,
+        txn_id: TxnId,
+        guard: Option<TxnLockWriteGuard<TxnId>>,
+        prior: Option<TxnId>,
+    ) -> Modified<TxnId> {
+        let lock = self.lock.clone();
+        let discard: Box<dyn FnOnce() + Send + Sync> = Box::new(move || lock.free(txn_id));
+
+        Modified {
+            guard,
+            discard: prior.as_ref().map(|_prior| discard),
+            prior,
+        }
+    }
+}
+
+impl<TxnId, FE> File<TxnId, FE> {
+    // check whether the canonical version of this file was changed outside of the transactional
+    // filesystem since it was last loaded or synchronized
+    pub(super) async fn check_external(&self) -> Result<Option<ExternalChangeKind>> {
+        self.tracker
+            .check(self.lock.path())
+            .await
+            .map_err(Error::from)
+    }
+}
+
+// fail with `Error::Corrupt` if the canonical file at `lock` doesn't match its recorded checksum
+async fn verify<TxnId: fmt::Display>(lock: &LockContext<TxnId>, txn_id: &TxnId) -> Result<()> {
+    if checksum::verify(lock.path()).await? {
+        Ok(())
+    } else {
+        Err(Error::Corrupt(lock.location(txn_id)))
+    }
+}
+
 impl<TxnId, FE> fmt::Debug for File<TxnId, FE> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         #[cfg(debug_assertions)]
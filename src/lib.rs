@@ -1,21 +1,156 @@
 //! A transactional filesystem cache layer based on [`freqfs`].
 //! See the "examples" directory for usage examples.
+//!
+//! # Isolation
+//!
+//! Transactions are serializable in the order of their IDs: a transaction never observes
+//! the writes of a later transaction, and a transaction can't write anything which a later
+//! transaction has already read.
+//!
+//! Every read is recorded until its transaction is finalized, at the granularity of the read:
+//!  - a point lookup in a [`Dir`] (e.g. [`Dir::get_file`] or [`Dir::contains`]) records a read of
+//!    that one name, whether or not an entry is present, so it protects against a phantom entry
+//!    being created with that name but doesn't affect the creation of any other entry;
+//!  - a listing of a [`Dir`] (e.g. [`Dir::iter`], [`Dir::len`] or [`Dir::file_names`]) records
+//!    a read of every name in the [`Dir`], present or not;
+//!  - a read of a [`File`] (including a streaming or range read) records a read of its contents.
+//!
+//! The entries of a [`Dir`] are kept in a [`txn_lock::map::TxnMapLock`], which records a read
+//! of a single key separately from a read of the whole map, so these guarantees come from it.
+//!
+//! The only exception is [`Dir::iter_snapshot`], which lists the committed entries of a [`Dir`]
+//! without recording a read, so that it doesn't block or conflict with concurrent writers.
+//!
+//! By default, a write which conflicts with a recorded read fails immediately with
+//! [`Error::Conflict`]. In optimistic mode (see [`DirOptions::optimistic`]) the contents of files
+//! are not locked; instead, each commit is validated against the recorded reads and fails with
+//! [`Error::Conflict`] if it would break serializability. In either case the transaction should
+//! be rolled back and retried with a new ID, e.g. using [`run_txn`].
 
+use std::path::{Path, PathBuf};
 use std::{fmt, io};
 
-pub use dir::{Dir, DirEntry, Key, VERSIONS};
-pub use file::{File, FileVersionRead, FileVersionWrite};
+#[cfg(feature = "stream")]
+pub use block::BlockFile;
+pub use dir::{Dir, DirEntry, DirOptions, Key, DEFAULT_LOAD_CONCURRENCY, LOCK, VERSIONS};
+pub use external::{ExternalChange, ExternalChangeKind, ExternalWatcher};
+pub use file::{File, FileVersionRead, FileVersionReader, FileVersionWrite, FileVersionWriter};
 pub use hr_id::Id;
+pub use sync::Durability;
+pub use txn::{run_txn, run_txn_with, RetryPolicy, DEFAULT_MAX_ATTEMPTS};
+pub use txn_id::{CounterTxnId, HlcTxnId, NextTxnId, NodeTxnId, TxnIdSource};
 
+#[cfg(feature = "stream")]
+mod block;
+mod budget;
+mod checksum;
 mod dir;
+mod external;
 mod file;
+mod lock;
+mod sync;
+mod txn;
+mod txn_id;
+
+/// The location of an [`Error`]: the path of the entry and the transaction which encountered it
+#[derive(Clone, Debug)]
+pub struct Location {
+    path: PathBuf,
+    txn_id: String,
+}
+
+impl Location {
+    pub(crate) fn new<TxnId: fmt::Display>(path: PathBuf, txn_id: &TxnId) -> Self {
+        Self {
+            path,
+            txn_id: txn_id.to_string(),
+        }
+    }
+
+    /// The full path of the entry where the error occurred.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The ID of the transaction which encountered the error.
+    pub fn txn_id(&self) -> &str {
+        &self.txn_id
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.path.display(), self.txn_id)
+    }
+}
 
 /// An error encountered during a transactional filesystem operation
+///
+/// New variants may be added without a breaking release, so a `match` on an [`Error`]
+/// needs a wildcard arm.
+#[non_exhaustive]
 pub enum Error {
-    Conflict(txn_lock::Error),
+    AlreadyExists(Location),
+    Committed(Location),
+    Conflict(Location),
+    Corrupt(Location),
     IO(io::Error),
-    NotFound(String),
+    InvalidName(Location),
+    Locked(Location),
+    MemoryLimit(Location),
+    NotADirectory(Location),
+    NotAFile(Location),
+    NotFound(Location),
+    NotUpgradable(Location),
+    Outdated(Location),
     Parse(hr_id::ParseError),
+    ReadOnly(Location),
+    Timeout(Location),
+    WouldBlock(Location),
+}
+
+impl Error {
+    pub(crate) fn from_txn_lock(cause: txn_lock::Error, location: Location) -> Self {
+        match cause {
+            txn_lock::Error::Committed => Self::Committed(location),
+            txn_lock::Error::Conflict => Self::Conflict(location),
+            txn_lock::Error::Outdated => Self::Outdated(location),
+            txn_lock::Error::WouldBlock => Self::WouldBlock(location),
+            txn_lock::Error::Background(cause) => Self::IO(io::Error::other(cause)),
+        }
+    }
+
+    /// Return the [`Location`] where this error occurred, if known.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Self::AlreadyExists(location)
+            | Self::Committed(location)
+            | Self::Conflict(location)
+            | Self::Corrupt(location)
+            | Self::InvalidName(location)
+            | Self::Locked(location)
+            | Self::MemoryLimit(location)
+            | Self::NotADirectory(location)
+            | Self::NotAFile(location)
+            | Self::NotFound(location)
+            | Self::NotUpgradable(location)
+            | Self::Outdated(location)
+            | Self::ReadOnly(location)
+            | Self::Timeout(location)
+            | Self::WouldBlock(location) => Some(location),
+            Self::IO(_) | Self::Parse(_) => None,
+        }
+    }
+
+    /// Return `true` if this error was caused by a conflict with another transaction.
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, Self::Conflict(_) | Self::Outdated(_))
+    }
+
+    /// Return `true` if the failed operation might succeed if retried in a new transaction.
+    pub fn is_retryable(&self) -> bool {
+        self.is_conflict() || matches!(self, Self::Timeout(_) | Self::WouldBlock(_))
+    }
 }
 
 impl From<hr_id::ParseError> for Error {
@@ -30,19 +165,32 @@ impl From<io::Error> for Error {
     }
 }
 
-impl From<txn_lock::Error> for Error {
-    fn from(cause: txn_lock::Error) -> Self {
-        Self::Conflict(cause)
-    }
-}
-
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::Conflict(cause) => cause.fmt(f),
+            Self::AlreadyExists(location) => write!(f, "there is already an entry at {location}"),
+            Self::Committed(location) => write!(f, "already committed: {location}"),
+            Self::Conflict(location) => write!(f, "conflicting transactional lock: {location}"),
+            Self::Corrupt(location) => write!(f, "checksum mismatch: {location}"),
             Self::IO(cause) => cause.fmt(f),
-            Self::NotFound(locator) => write!(f, "not found: {locator}"),
+            Self::InvalidName(location) => write!(f, "invalid name: {location}"),
+            Self::Locked(location) => {
+                write!(f, "locked by another process: {location}")
+            }
+            Self::MemoryLimit(location) => {
+                write!(f, "transaction exceeded its memory limit: {location}")
+            }
+            Self::NotADirectory(location) => write!(f, "not a directory: {location}"),
+            Self::NotAFile(location) => write!(f, "not a file: {location}"),
+            Self::NotFound(location) => write!(f, "not found: {location}"),
+            Self::NotUpgradable(location) => {
+                write!(f, "a read lock can't be upgraded: {location}")
+            }
+            Self::Outdated(location) => write!(f, "already finalized: {location}"),
             Self::Parse(cause) => cause.fmt(f),
+            Self::ReadOnly(location) => write!(f, "read-only filesystem: {location}"),
+            Self::Timeout(location) => write!(f, "timed out waiting for a lock: {location}"),
+            Self::WouldBlock(location) => write!(f, "synchronous locking failed: {location}"),
         }
     }
 }
@@ -0,0 +1,325 @@
+use std::any::Any;
+use std::future::Future;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use std::{fmt, io};
+
+use freqfs::{Cache, FileSave};
+use futures::lock::{Mutex as CommitLock, MutexGuard as CommitGuard};
+use hr_id::Id;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::budget::{Budget, Charge};
+use super::dir::DEFAULT_LOAD_CONCURRENCY;
+use super::sync::{Durability, GroupCommit, Syncs};
+use super::{Error, Location, Result};
+
+/// The lock acquisition policy shared by every [`crate::Dir`] and [`crate::File`]
+/// in a transactional filesystem
+pub(crate) struct LockPolicy<TxnId> {
+    timeout: Option<Duration>,
+    // in optimistic mode, serializes the validation and installation of each commit
+    commit: Option<CommitLock<()>>,
+    // if set, batches the syncs of concurrent commits
+    group: Option<GroupCommit>,
+    // the default durability of a commit
+    durability: Durability,
+    // the first error encountered by a background sync, returned by the next commit
+    background_error: Arc<std::sync::Mutex<Option<io::Error>>>,
+    // the memory used by the uncommitted versions of each pending transaction
+    budget: Budget<TxnId>,
+    // if set, every mutation is rejected and nothing is written to the filesystem
+    read_only: bool,
+    // if set, the checksum of each canonical file is recorded when it's synchronized
+    checksums: bool,
+    // the advisory lock on the root directory, held until this policy is dropped
+    _root_lock: Option<std::fs::File>,
+    // the cache used to construct handles to files staged outside of the transactional cache
+    staging: OnceLock<Arc<dyn Any + Send + Sync>>,
+    // the directory where files are staged, which the transactional cache never loads
+    staging_dir: Option<PathBuf>,
+    // bounds the number of entries loaded concurrently across the whole tree
+    loads: Arc<Semaphore>,
+}
+
+impl<TxnId> LockPolicy<TxnId> {
+    pub fn new(
+        timeout: Option<Duration>,
+        optimistic: bool,
+        group: Option<(Duration, usize)>,
+        durability: Durability,
+        memory_limit: Option<usize>,
+        read_only: bool,
+    ) -> Self {
+        Self {
+            timeout,
+            commit: if optimistic {
+                Some(CommitLock::new(()))
+            } else {
+                None
+            },
+            group: group.map(|(window, max_batch)| GroupCommit::new(window, max_batch)),
+            durability,
+            background_error: Arc::new(std::sync::Mutex::new(None)),
+            budget: Budget::new(memory_limit),
+            read_only,
+            checksums: false,
+            _root_lock: None,
+            staging: OnceLock::new(),
+            staging_dir: None,
+            loads: Arc::new(Semaphore::new(DEFAULT_LOAD_CONCURRENCY)),
+        }
+    }
+
+    /// Load at most `max_concurrency` entries at once, across every directory being loaded.
+    pub fn with_load_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.loads = Arc::new(Semaphore::new(max_concurrency));
+        self
+    }
+
+    /// Record and verify the checksum of each canonical file if `checksums` is `true`.
+    pub fn with_checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Stage new files, e.g. those written by a [`crate::FileVersionWriter`], in `staging_dir`.
+    pub fn with_staging_dir(mut self, staging_dir: PathBuf) -> Self {
+        self.staging_dir = Some(staging_dir);
+        self
+    }
+
+    /// Hold the given advisory `root_lock` until this policy is dropped.
+    pub fn with_root_lock(mut self, root_lock: Option<std::fs::File>) -> Self {
+        self._root_lock = root_lock;
+        self
+    }
+}
+
+/// The transactional lock of a single [`crate::Dir`] or [`crate::File`],
+/// acquired according to a shared [`LockPolicy`]
+pub(crate) struct LockContext<TxnId> {
+    path: Arc<PathBuf>,
+    policy: Arc<LockPolicy<TxnId>>,
+}
+
+impl<TxnId> Clone for LockContext<TxnId> {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+impl<TxnId> LockContext<TxnId> {
+    pub fn new(policy: Arc<LockPolicy<TxnId>>, path: PathBuf) -> Self {
+        Self {
+            path: Arc::new(path),
+            policy,
+        }
+    }
+
+    /// Construct a new [`LockContext`] for the entry with the given `name` under this one.
+    pub fn child(&self, name: &Id) -> Self {
+        Self::new(self.policy.clone(), self.path.join(name.as_str()))
+    }
+
+    /// The full path of the entry guarded by this lock.
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn policy(&self) -> &Arc<LockPolicy<TxnId>> {
+        &self.policy
+    }
+
+    /// Return `true` if file contents are written optimistically and validated at commit time.
+    pub fn is_optimistic(&self) -> bool {
+        self.policy.commit.is_some()
+    }
+
+    /// Return `true` if the filesystem is mounted read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.policy.read_only
+    }
+
+    /// Return `true` if the checksum of each canonical file is recorded and verified.
+    pub fn checksums(&self) -> bool {
+        self.policy.checksums
+    }
+
+    /// Return a [`Cache`] to construct a handle to a file which hasn't been loaded yet,
+    /// e.g. one staged by a [`crate::FileVersionWriter`]. No file is ever loaded into this cache.
+    pub fn staging<FE>(&self) -> Arc<Cache<FE>>
+    where
+        FE: for<'a> FileSave<'a>,
+    {
+        // freqfs can only construct a handle to a file on disk through a cache
+        let staging = self.policy.staging.get_or_init(|| {
+            let cache: Arc<dyn Any + Send + Sync> = Cache::<FE>::new(0, Some(1));
+            cache
+        });
+
+        staging.clone().downcast().expect("staging cache")
+    }
+
+    /// The directory where new files are staged before they're copied into the cache.
+    ///
+    /// Panics: if the filesystem is mounted read-only, since nothing is ever staged then
+    pub fn staging_dir(&self) -> &PathBuf {
+        self.policy.staging_dir.as_ref().expect("staging dir")
+    }
+
+    /// Wait for permission to load an entry, subject to the load concurrency limit.
+    /// The permit must not be held while loading the contents of a sub-directory.
+    pub async fn load_permit(&self) -> OwnedSemaphorePermit {
+        let loads = self.policy.loads.clone();
+        loads.acquire_owned().await.expect("load permit")
+    }
+
+    /// In optimistic mode, wait for exclusive permission to validate and install a commit.
+    pub async fn commit_permit(&self) -> Option<CommitGuard<'_, ()>> {
+        if let Some(commit) = &self.policy.commit {
+            Some(commit.lock().await)
+        } else {
+            None
+        }
+    }
+
+    /// The default [`Durability`] of a commit.
+    pub fn durability(&self) -> Durability {
+        self.policy.durability
+    }
+
+    /// Synchronize the given `syncs` with the filesystem according to the given `durability`,
+    /// together with those of any other commits in the same group if group commit is enabled.
+    ///
+    /// If an earlier background sync failed, its error is returned here.
+    pub async fn sync(&self, syncs: Syncs, durability: Durability) -> io::Result<()> {
+        match durability {
+            Durability::Sync => {
+                if let Some(group) = &self.policy.group {
+                    group.sync(syncs).await
+                } else {
+                    syncs.run().await
+                }
+            }
+            Durability::Async => syncs.spawn(self.policy.background_error.clone()),
+            Durability::None => syncs.discard(),
+        }?;
+
+        let mut background_error = self.policy.background_error.lock().expect("sync error");
+
+        if let Some(cause) = background_error.take() {
+            Err(cause)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<TxnId: fmt::Display> LockContext<TxnId> {
+    /// Return the [`Location`] of this lock at `txn_id`.
+    pub fn location(&self, txn_id: &TxnId) -> Location {
+        Location::new(PathBuf::clone(&self.path), txn_id)
+    }
+
+    /// Return the [`Location`] of the entry with the given `name` under this lock at `txn_id`.
+    pub fn location_of(&self, txn_id: &TxnId, name: &Id) -> Location {
+        Location::new(self.path.join(name.as_str()), txn_id)
+    }
+
+    /// Fail with [`Error::ReadOnly`] if the filesystem is mounted read-only.
+    pub fn check_writable(&self, txn_id: &TxnId) -> Result<()> {
+        if self.policy.read_only {
+            Err(Error::ReadOnly(self.location(txn_id)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wait for the given `lock` future, e.g. a lock on a directory or file in the cache,
+    /// subject to the timeout policy.
+    pub async fn wait<T, Fut>(&self, txn_id: &TxnId, lock: Fut) -> Result<T>
+    where
+        Fut: Future<Output = T>,
+    {
+        if let Some(timeout) = self.policy.timeout {
+            tokio::time::timeout(timeout, lock)
+                .await
+                .map_err(|_| Error::Timeout(self.location(txn_id)))
+        } else {
+            Ok(lock.await)
+        }
+    }
+
+    /// Construct an [`Error`] at this lock from a transactional lock error.
+    pub fn error(&self, txn_id: &TxnId, cause: txn_lock::Error) -> Error {
+        Error::from_txn_lock(cause, self.location(txn_id))
+    }
+
+    /// Construct an [`Error`] at this lock from an I/O error encountered while locking.
+    pub fn io_error(&self, txn_id: &TxnId, cause: io::Error) -> Error {
+        if cause.kind() == io::ErrorKind::WouldBlock {
+            Error::WouldBlock(self.location(txn_id))
+        } else {
+            Error::IO(cause)
+        }
+    }
+}
+
+impl<TxnId: Copy + Hash + Ord> LockContext<TxnId> {
+    /// Drop the memory charged to `txn_id` for an uncommitted version of this entry, if any.
+    pub fn free(&self, txn_id: TxnId) {
+        self.policy.budget.free(&txn_id, &self.path);
+    }
+
+    /// Drop the memory charged to every transaction earlier than `txn_id`.
+    pub fn finalize_budget(&self, txn_id: TxnId) {
+        self.policy.budget.finalize(&txn_id);
+    }
+
+    /// Return the number of bytes used by the uncommitted versions written by `txn_id`.
+    pub fn memory_usage(&self, txn_id: TxnId) -> usize {
+        self.policy.budget.usage(&txn_id)
+    }
+
+    /// Return the number of bytes used by the uncommitted versions of each pending transaction.
+    pub fn memory_usage_by_txn(&self) -> Vec<(TxnId, usize)> {
+        self.policy.budget.usage_by_txn()
+    }
+}
+
+impl<TxnId: Copy + Hash + Ord + fmt::Display> LockContext<TxnId> {
+    /// Charge `txn_id` for a new version of `size` bytes of this entry,
+    /// or fail with [`Error::MemoryLimit`] if this would exceed its memory limit.
+    pub fn reserve(&self, txn_id: TxnId, size: usize) -> Result<Charge> {
+        self.policy
+            .budget
+            .reserve(txn_id, &self.path, size)
+            .ok_or_else(|| Error::MemoryLimit(self.location(&txn_id)))
+    }
+
+    /// Return the charge to `txn_id` for its existing version of this entry, if any,
+    /// or fail with [`Error::MemoryLimit`] if `txn_id` has already exceeded its memory limit.
+    pub fn charge(&self, txn_id: TxnId) -> Result<Option<Charge>> {
+        if self.policy.budget.is_exceeded(&txn_id) {
+            Err(Error::MemoryLimit(self.location(&txn_id)))
+        } else {
+            Ok(self.policy.budget.charged(&txn_id, &self.path))
+        }
+    }
+
+    /// Wait for the given transactional `lock` future, subject to the timeout policy.
+    pub async fn acquire<T, Fut>(&self, txn_id: TxnId, lock: Fut) -> Result<T>
+    where
+        Fut: Future<Output = std::result::Result<T, txn_lock::Error>>,
+    {
+        self.wait(&txn_id, lock)
+            .await?
+            .map_err(|cause| self.error(&txn_id, cause))
+    }
+}
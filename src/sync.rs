@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use freqfs::{DirLock, FileLock, FileSave};
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture, FutureExt};
+
+use super::checksum;
+use super::external::Tracker;
+
+/// How durable the state committed by a transaction must be before its commit completes
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Durability {
+    /// Synchronize the committed state with the filesystem before the commit completes.
+    #[default]
+    Sync,
+    /// Synchronize the committed state with the filesystem in the background,
+    /// so that the commit completes without waiting for any I/O.
+    /// If this fails, the error is returned by the next commit to the same filesystem.
+    Async,
+    /// Don't synchronize the committed state with the filesystem; it will be written
+    /// when the cache evicts it or when a later commit synchronizes the same files.
+    None,
+}
+
+type SyncTask = Box<dyn FnOnce() -> BoxFuture<'static, io::Result<()>> + Send>;
+
+/// The canonical files and directories which a commit needs to synchronize with the filesystem,
+/// at most once per path
+#[derive(Default)]
+pub(crate) struct Syncs {
+    tasks: Mutex<BTreeMap<PathBuf, SyncTask>>,
+    // the first error encountered while removing an out-of-date checksum, if any
+    error: Mutex<Option<io::Error>>,
+}
+
+impl Syncs {
+    /// Remove the recorded checksum of the canonical file at `path` before it's replaced,
+    /// since the new file could be written at any time after that, e.g. if the cache evicts it.
+    /// Any error is returned when this set is synchronized.
+    pub async fn invalidate(&self, path: &Path) {
+        if let Err(cause) = checksum::remove(path).await {
+            let mut error = self.error.lock().expect("sync error");
+            error.get_or_insert(cause);
+        }
+    }
+
+    /// Synchronize the given canonical `file`, or remove it from the filesystem if it was deleted,
+    /// then record its new checksum if `checksum` is `true` and its new state with the given
+    /// `tracker`, if any.
+    pub fn file<FE>(&self, file: FileLock<FE>, tracker: Option<&Tracker>, checksum: bool)
+    where
+        FE: for<'a> FileSave<'a>,
+    {
+        let path = file.path().to_path_buf();
+        let record = tracker.map(|tracker| (tracker.invalidate(), tracker.clone()));
+
+        let task: SyncTask = Box::new(move || {
+            async move {
+                file.sync().await?;
+
+                if checksum {
+                    checksum::record(file.path()).await?;
+                }
+
+                if let Some((generation, tracker)) = record {
+                    tracker.record(file.path(), generation).await?;
+                }
+
+                Ok(())
+            }
+            .boxed()
+        });
+
+        let mut tasks = self.tasks.lock().expect("syncs");
+        tasks.insert(path, task);
+    }
+
+    /// Synchronize the canonical directory at `path`, including any deleted entries.
+    pub fn dir<FE>(&self, path: PathBuf, dir: DirLock<FE>)
+    where
+        FE: for<'a> FileSave<'a>,
+    {
+        let task: SyncTask = Box::new(move || async move { dir.sync().await }.boxed());
+
+        let mut tasks = self.tasks.lock().expect("syncs");
+        tasks.insert(path, task);
+    }
+
+    /// Discard this set without synchronizing it.
+    pub fn discard(self) -> io::Result<()> {
+        self.into_tasks().map(|_tasks| ())
+    }
+
+    fn into_tasks(self) -> io::Result<BTreeMap<PathBuf, SyncTask>> {
+        if let Some(cause) = self.error.into_inner().expect("sync error") {
+            Err(cause)
+        } else {
+            Ok(self.tasks.into_inner().expect("syncs"))
+        }
+    }
+
+    /// Synchronize every file and directory in this set, in order of their paths.
+    pub async fn run(self) -> io::Result<()> {
+        run(self.into_tasks()?).await
+    }
+
+    /// Synchronize every file and directory in this set in the background,
+    /// and record the error in `failed` if this fails (unless an earlier error is still there).
+    pub fn spawn(self, failed: Arc<Mutex<Option<io::Error>>>) -> io::Result<()> {
+        let tasks = self.into_tasks()?;
+
+        if tasks.is_empty() {
+            return Ok(());
+        }
+
+        tokio::spawn(async move {
+            if let Err(cause) = run(tasks).await {
+                #[cfg(feature = "logging")]
+                log::error!("background sync failed: {cause}");
+
+                let mut failed = failed.lock().expect("sync error");
+                failed.get_or_insert(cause);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+async fn run(tasks: BTreeMap<PathBuf, SyncTask>) -> io::Result<()> {
+    for (_path, task) in tasks {
+        task().await?;
+    }
+
+    Ok(())
+}
+
+struct Batch {
+    tasks: BTreeMap<PathBuf, SyncTask>,
+    waiters: Vec<oneshot::Sender<std::result::Result<(), (io::ErrorKind, String)>>>,
+    // signals the task which will flush the current batch, if any, that the batch is full
+    full: Option<oneshot::Sender<()>>,
+}
+
+/// Makes the commits which arrive within a short window durable together
+pub(crate) struct GroupCommit {
+    window: Duration,
+    max_batch: usize,
+    batch: Arc<Mutex<Batch>>,
+}
+
+impl GroupCommit {
+    pub fn new(window: Duration, max_batch: usize) -> Self {
+        Self {
+            window,
+            max_batch,
+            batch: Arc::new(Mutex::new(Batch {
+                tasks: BTreeMap::new(),
+                waiters: Vec::new(),
+                full: None,
+            })),
+        }
+    }
+
+    /// Add the given `syncs` to the current batch and wait until the batch is synchronized.
+    ///
+    /// The first commit in each batch spawns a task to flush it, so that the batch is still
+    /// synchronized, and the other commits in it notified, if that commit stops waiting.
+    pub async fn sync(&self, syncs: Syncs) -> io::Result<()> {
+        let tasks = syncs.into_tasks()?;
+        let (tx, rx) = oneshot::channel();
+
+        {
+            let mut batch = self.batch.lock().expect("group commit");
+            batch.tasks.extend(tasks);
+            batch.waiters.push(tx);
+
+            if batch.waiters.len() == 1 {
+                let (full, is_full) = oneshot::channel();
+                batch.full = Some(full);
+
+                let flush = flush(self.batch.clone(), self.window, self.max_batch, is_full);
+                tokio::spawn(flush);
+            } else if batch.waiters.len() >= self.max_batch {
+                if let Some(full) = batch.full.take() {
+                    // the flush task might have stopped waiting already
+                    let _ = full.send(());
+                }
+            }
+        }
+
+        match rx.await {
+            Ok(result) => result.map_err(|(kind, message)| io::Error::new(kind, message)),
+            Err(oneshot::Canceled) => Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "group commit was cancelled",
+            )),
+        }
+    }
+}
+
+// wait for the `window` to elapse or for the `batch` to fill up,
+// then synchronize each path in the batch once and notify each commit in it
+async fn flush(
+    batch: Arc<Mutex<Batch>>,
+    window: Duration,
+    max_batch: usize,
+    is_full: oneshot::Receiver<()>,
+) {
+    if max_batch > 1 {
+        let window = pin!(tokio::time::sleep(window));
+        future::select(window, is_full).await;
+    }
+
+    let (tasks, waiters) = {
+        let mut batch = batch.lock().expect("group commit");
+        batch.full = None;
+
+        let tasks = std::mem::take(&mut batch.tasks);
+        let waiters = std::mem::take(&mut batch.waiters);
+        (tasks, waiters)
+    };
+
+    #[cfg(feature = "logging")]
+    log::debug!(
+        "group commit of {} transactions syncing {} paths",
+        waiters.len(),
+        tasks.len()
+    );
+
+    let result = run(tasks)
+        .await
+        .map_err(|cause| (cause.kind(), cause.to_string()));
+
+    for waiter in waiters {
+        // the commit might have stopped waiting
+        let _ = waiter.send(result.clone());
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::time::Duration;
+
+use freqfs::{FileSave, Name};
+
+use super::dir::Dir;
+use super::{Error, Result};
+
+/// The default maximum number of times [`run_txn`] will attempt a transaction
+pub const DEFAULT_MAX_ATTEMPTS: usize = 5;
+
+/// Configures how [`run_txn_with`] retries a transaction which fails with a retryable error
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Set the maximum number of attempts, including the first.
+    ///
+    /// Panics: if `max_attempts` is zero
+    pub fn max_attempts(mut self, max_attempts: usize) -> Self {
+        assert!(
+            max_attempts > 0,
+            "invalid config for max_attempts: {}",
+            max_attempts
+        );
+
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set how long to wait before the first retry.
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    /// Set the maximum time to wait between attempts.
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Set the factor by which the backoff increases after each failed attempt.
+    ///
+    /// Panics: if `multiplier` is zero
+    pub fn multiplier(mut self, multiplier: u32) -> Self {
+        assert!(
+            multiplier > 0,
+            "invalid config for multiplier: {}",
+            multiplier
+        );
+
+        self.multiplier = multiplier;
+        self
+    }
+}
+
+/// Run the transaction `op` against `root` with the default [`RetryPolicy`].
+/// See [`run_txn_with`] for details.
+pub async fn run_txn<TxnId, FE, T, Ids, Op, Fut>(
+    root: &Dir<TxnId, FE>,
+    id_source: Ids,
+    op: Op,
+) -> Result<T>
+where
+    TxnId: Name + PartialOrd<str> + Hash + Copy + Ord + fmt::Display + fmt::Debug + Send + Sync,
+    FE: for<'a> FileSave<'a> + Clone,
+    Ids: FnMut() -> TxnId,
+    Op: FnMut(TxnId) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    run_txn_with(root, id_source, RetryPolicy::default(), op).await
+}
+
+/// Run the transaction `op` against `root`, with a new transaction ID from `id_source`
+/// for each attempt.
+///
+/// If `op` succeeds, its transaction is committed recursively and its result is returned.
+/// Otherwise, or if nothing was committed because the commit failed (see [`Dir::commit`]),
+/// its transaction is rolled back recursively; if the error is retryable
+/// (see [`crate::Error::is_retryable`]) `op` is retried after a backoff, up to the maximum number
+/// of attempts allowed by the given `policy`. The last error is returned if no attempt succeeds.
+/// If the commit fails with an [`Error::IO`] after committing, that error is returned
+/// immediately, since the transaction can't be rolled back.
+///
+/// This never finalizes a transaction: the caller must still call [`Dir::finalize`] once the
+/// transaction of each attempt, including any which were rolled back, is no longer needed.
+pub async fn run_txn_with<TxnId, FE, T, Ids, Op, Fut>(
+    root: &Dir<TxnId, FE>,
+    mut id_source: Ids,
+    policy: RetryPolicy,
+    mut op: Op,
+) -> Result<T>
+where
+    TxnId: Name + PartialOrd<str> + Hash + Copy + Ord + fmt::Display + fmt::Debug + Send + Sync,
+    FE: for<'a> FileSave<'a> + Clone,
+    Ids: FnMut() -> TxnId,
+    Op: FnMut(TxnId) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = policy.initial_backoff;
+    let mut attempt = 1;
+
+    loop {
+        let txn_id = id_source();
+
+        let result = match op(txn_id).await {
+            Ok(result) => match root.commit(txn_id, true).await {
+                Ok(()) => Ok(result),
+                Err(cause @ Error::IO(_)) => return Err(cause),
+                Err(cause) => Err(cause),
+            },
+            Err(cause) => Err(cause),
+        };
+
+        match result {
+            Ok(result) => return Ok(result),
+            Err(cause) => {
+                root.rollback(txn_id, true).await;
+
+                if !cause.is_retryable() || attempt >= policy.max_attempts {
+                    return Err(cause);
+                }
+
+                #[cfg(feature = "logging")]
+                log::debug!("retrying transaction {txn_id:?} after error: {cause}");
+            }
+        }
+
+        tokio::time::sleep(backoff).await;
+
+        backoff = Ord::min(
+            backoff.saturating_mul(policy.multiplier),
+            policy.max_backoff,
+        );
+        attempt += 1;
+    }
+}
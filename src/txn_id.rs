@@ -0,0 +1,274 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Error;
+
+/// A transaction ID type which can generate its own successor
+pub trait NextTxnId: Copy + Ord {
+    /// Return a new transaction ID greater than this one.
+    ///
+    /// Panics: if there is no greater transaction ID
+    fn next(&self) -> Self;
+
+    /// Merge an `observed` transaction ID from another source into this one,
+    /// so that the [`NextTxnId::next`] ID of the result is greater than both.
+    fn observe(&self, observed: &Self) -> Self {
+        Ord::max(*self, *observed)
+    }
+}
+
+/// A transaction ID from a monotonic counter
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct CounterTxnId(u64);
+
+impl CounterTxnId {
+    /// Construct a new [`CounterTxnId`] with the given `value`.
+    pub const fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    /// Return the value of this counter.
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for CounterTxnId {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl NextTxnId for CounterTxnId {
+    fn next(&self) -> Self {
+        Self(self.0.checked_add(1).expect("transaction ID overflow"))
+    }
+}
+
+impl fmt::Display for CounterTxnId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:020}", self.0)
+    }
+}
+
+impl FromStr for CounterTxnId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_int(s).map(Self)
+    }
+}
+
+/// A transaction ID from a hybrid logical clock, i.e. a physical timestamp in milliseconds
+/// since the Unix epoch with a logical counter to order transactions within the same millisecond
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct HlcTxnId {
+    physical: u64,
+    logical: u32,
+}
+
+impl HlcTxnId {
+    /// Construct a new [`HlcTxnId`] with the given `physical` timestamp and `logical` counter.
+    pub const fn new(physical: u64, logical: u32) -> Self {
+        Self { physical, logical }
+    }
+
+    /// Return the physical timestamp of this [`HlcTxnId`], in milliseconds since the Unix epoch.
+    pub fn physical(&self) -> u64 {
+        self.physical
+    }
+
+    /// Return the logical counter of this [`HlcTxnId`].
+    pub fn logical(&self) -> u32 {
+        self.logical
+    }
+}
+
+impl NextTxnId for HlcTxnId {
+    fn next(&self) -> Self {
+        let now = now().as_millis() as u64;
+
+        if now > self.physical {
+            Self::new(now, 0)
+        } else if self.logical < u32::MAX {
+            Self::new(self.physical, self.logical + 1)
+        } else {
+            let physical = self.physical.checked_add(1);
+            Self::new(physical.expect("transaction ID overflow"), 0)
+        }
+    }
+}
+
+impl fmt::Display for HlcTxnId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:020}-{:010}", self.physical, self.logical)
+    }
+}
+
+impl FromStr for HlcTxnId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_pair(s).map(|(physical, logical)| Self::new(physical, logical))
+    }
+}
+
+/// A transaction ID made of a timestamp in nanoseconds since the Unix epoch
+/// and the ID of the node which created it, to order transactions across several nodes
+#[derive(Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct NodeTxnId {
+    nanos: u64,
+    node: u32,
+}
+
+impl NodeTxnId {
+    /// Construct a new [`NodeTxnId`] with the given timestamp in `nanos` and `node` ID.
+    pub const fn new(nanos: u64, node: u32) -> Self {
+        Self { nanos, node }
+    }
+
+    /// Return the timestamp of this [`NodeTxnId`], in nanoseconds since the Unix epoch.
+    pub fn nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    /// Return the ID of the node which created this [`NodeTxnId`].
+    pub fn node(&self) -> u32 {
+        self.node
+    }
+}
+
+impl NextTxnId for NodeTxnId {
+    fn next(&self) -> Self {
+        let now = now().as_nanos() as u64;
+        let nanos = self.nanos.checked_add(1).expect("transaction ID overflow");
+        Self::new(Ord::max(now, nanos), self.node)
+    }
+
+    // keep the ID of this node, since only this node's IDs are unique to it
+    fn observe(&self, observed: &Self) -> Self {
+        Self::new(Ord::max(self.nanos, observed.nanos), self.node)
+    }
+}
+
+impl fmt::Display for NodeTxnId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:020}-{:010}", self.nanos, self.node)
+    }
+}
+
+impl FromStr for NodeTxnId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_pair(s).map(|(nanos, node)| Self::new(nanos, node))
+    }
+}
+
+// implement comparison with the names of version files, which are ordered lexicographically,
+// so each of these types has a fixed-width string representation
+macro_rules! txn_id_name {
+    ($t:ty, $len:expr) => {
+        impl $t {
+            fn cmp_str(&self, other: &str) -> Ordering {
+                let mut name = [0u8; $len];
+                write!(&mut name[..], "{}", self).expect("transaction ID");
+                name[..].cmp(other.as_bytes())
+            }
+        }
+
+        impl freqfs::Name for $t {
+            fn partial_cmp(&self, key: &String) -> Option<Ordering> {
+                Some(self.cmp_str(key))
+            }
+        }
+
+        impl PartialEq<str> for $t {
+            fn eq(&self, other: &str) -> bool {
+                self.cmp_str(other) == Ordering::Equal
+            }
+        }
+
+        impl PartialOrd<str> for $t {
+            fn partial_cmp(&self, other: &str) -> Option<Ordering> {
+                Some(self.cmp_str(other))
+            }
+        }
+    };
+}
+
+txn_id_name!(CounterTxnId, 20);
+txn_id_name!(HlcTxnId, 31);
+txn_id_name!(NodeTxnId, 31);
+
+/// A source of unique, increasing transaction IDs which is safe to share across threads
+pub struct TxnIdSource<TxnId> {
+    last: Mutex<TxnId>,
+}
+
+impl<TxnId: NextTxnId> TxnIdSource<TxnId> {
+    /// Construct a new [`TxnIdSource`] whose IDs will all be greater than `last`.
+    pub fn new(last: TxnId) -> Self {
+        Self {
+            last: Mutex::new(last),
+        }
+    }
+
+    /// Return a new transaction ID greater than any previously issued or observed.
+    pub fn next(&self) -> TxnId {
+        let mut last = self.last.lock().expect("last transaction ID");
+        *last = last.next();
+        *last
+    }
+
+    /// Observe a transaction ID from another source,
+    /// so that every ID issued after this call will be greater than `txn_id`.
+    pub fn observe(&self, txn_id: TxnId) {
+        let mut last = self.last.lock().expect("last transaction ID");
+
+        *last = last.observe(&txn_id);
+    }
+}
+
+impl<TxnId: NextTxnId + Default> Default for TxnIdSource<TxnId> {
+    fn default() -> Self {
+        Self::new(TxnId::default())
+    }
+}
+
+impl<TxnId: fmt::Debug> fmt::Debug for TxnIdSource<TxnId> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let last = self.last.lock().expect("last transaction ID");
+        write!(f, "transaction ID source (last issued {:?})", *last)
+    }
+}
+
+// parse the fixed-width string representation of an integer, e.g. the name of a version file
+fn parse_int<N: FromStr>(s: &str) -> Result<N, Error> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(hr_id::ParseError::from(format!("invalid transaction ID: {s}")).into());
+    }
+
+    s.parse()
+        .map_err(|_| hr_id::ParseError::from(format!("invalid transaction ID: {s}")).into())
+}
+
+// parse a transaction ID of the form "<u64>-<u32>"
+fn parse_pair(s: &str) -> Result<(u64, u32), Error> {
+    let (first, second) = s
+        .split_once('-')
+        .ok_or_else(|| hr_id::ParseError::from(format!("invalid transaction ID: {s}")))?;
+
+    Ok((parse_int(first)?, parse_int(second)?))
+}
+
+#[inline]
+fn now() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time")
+}
@@ -0,0 +1,148 @@
+#![cfg(feature = "stream")]
+
+mod common;
+
+use std::time::Duration;
+
+use common::*;
+use txfs::{BlockFile, Dir, Error, TxnIdSource};
+
+const BLOCK_SIZE: usize = 4;
+
+async fn setup(tmp: &TmpDir, txn_id: TxnId) -> Result<BlockFile<TxnId, File>, Error> {
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let blocks = root.create_dir(txn_id, id("blocks")).await?;
+    Ok(BlockFile::new(blocks, BLOCK_SIZE))
+}
+
+#[tokio::test]
+async fn test_partial_block_writes() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let file = setup(&tmp, txn_id).await?;
+
+    file.write(txn_id, 0, b"abcdefghij").await?;
+    assert_eq!(file.len(txn_id).await?, 10);
+
+    // a write which starts and ends in the middle of a block
+    file.write(txn_id, 3, b"XYZ").await?;
+    assert_eq!(file.read(txn_id, 0, 100).await?, b"abcXYZghij");
+    assert_eq!(file.read(txn_id, 2, 5).await?, b"cXYZg");
+
+    // a write past the end of the file fills the gap with zeros
+    file.write(txn_id, 12, b"kl").await?;
+    assert_eq!(file.read(txn_id, 8, 100).await?, b"ij\0\0kl");
+    assert_eq!(file.len(txn_id).await?, 14);
+
+    // a write which would end past the maximum length fails without changing anything
+    assert!(matches!(
+        file.write(txn_id, u64::MAX - 1, b"mno").await,
+        Err(Error::IO(cause)) if cause.kind() == std::io::ErrorKind::InvalidInput
+    ));
+    assert_eq!(file.len(txn_id).await?, 14);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shrink_and_grow() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let file = setup(&tmp, txn_id).await?;
+    file.write(txn_id, 0, b"abcdefghij").await?;
+
+    file.set_len(txn_id, 6).await?;
+    assert_eq!(file.len(txn_id).await?, 6);
+    assert_eq!(file.read(txn_id, 0, 100).await?, b"abcdef");
+
+    // growing the file in the same transaction re-uses the blocks past the old end
+    file.set_len(txn_id, 9).await?;
+    assert_eq!(file.len(txn_id).await?, 9);
+    assert_eq!(file.read(txn_id, 0, 100).await?, b"abcdef\0\0\0");
+
+    file.set_len(txn_id, 0).await?;
+    assert!(file.is_empty(txn_id).await?);
+
+    file.write(txn_id, 2, b"xyz").await?;
+    assert_eq!(file.read(txn_id, 0, 100).await?, b"\0\0xyz");
+
+    file.commit(txn_id).await?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(file.read(txn_id, 0, 100).await?, b"\0\0xyz");
+
+    file.set_len(txn_id, 1).await?;
+    file.set_len(txn_id, 2 * BLOCK_SIZE as u64 + 1).await?;
+    assert_eq!(file.read(txn_id, 0, 100).await?, &[0u8; 9]);
+
+    file.commit(txn_id).await?;
+    file.finalize(txn_id).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_commit_changed_blocks() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let file = setup(&tmp, txn_id).await?;
+    file.write(txn_id, 0, b"abcdefghijkl").await?;
+    file.commit(txn_id).await?;
+
+    let blocks = tmp.path().join("blocks");
+    let modified = |i: usize| {
+        std::fs::metadata(blocks.join(i.to_string()))
+            .unwrap()
+            .modified()
+    };
+    let before = [modified(0)?, modified(1)?, modified(2)?];
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    let txn_id = txn_ids.next();
+    file.write(txn_id, 5, b"F").await?;
+    file.commit(txn_id).await?;
+
+    // only the block which was written is synchronized
+    assert_eq!(modified(0)?, before[0]);
+    assert_ne!(modified(1)?, before[1]);
+    assert_eq!(modified(2)?, before[2]);
+
+    let txn_id = txn_ids.next();
+    assert_eq!(file.read(txn_id, 0, 100).await?, b"abcdeFghijkl");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_len_doesnt_read_blocks() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let file = setup(&tmp, txn_id).await?;
+    file.write(txn_id, 0, b"abcdefghij").await?;
+    file.commit(txn_id).await?;
+
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+
+    // reading the length at a later transaction doesn't read the last block...
+    assert_eq!(file.len(later).await?, 10);
+
+    // ...so an earlier transaction can still write it without changing the length
+    file.write(earlier, 8, b"IJ").await?;
+    file.commit(earlier).await?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(file.read(txn_id, 0, 100).await?, b"abcdefghIJ");
+    assert_eq!(file.len(txn_id).await?, 10);
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+mod common;
+
+use common::*;
+use txfs::{Dir, DirOptions, Durability, Error, TxnIdSource};
+
+const SIDECAR: &str = ".text.crc32";
+
+fn options() -> DirOptions<File> {
+    DirOptions::default().checksums(true)
+}
+
+#[tokio::test]
+async fn test_unreadable_checksum() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    {
+        let txn_id = txn_ids.next();
+        let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options()).await?;
+        root.create_file(txn_id, id("text"), Text::from("hello"))
+            .await?;
+
+        root.commit(txn_id, true).await?;
+    }
+
+    assert!(tmp.path().join(SIDECAR).exists());
+
+    // e.g. a sidecar which was only partly written before a crash
+    std::fs::write(tmp.path().join(SIDECAR), "not a checksum").expect("sidecar");
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options()).await?;
+    let file = root.get_file(txn_id, &id("text")).await?.expect("file");
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("hello"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checksum_removed_before_replace() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+    assert!(tmp.path().join(SIDECAR).exists());
+
+    // the canonical file could be written at any time once it's replaced,
+    // so its old checksum must already be gone even if it isn't synchronized
+    let txn_id = txn_ids.next();
+    file.write::<Text>(txn_id).await?.0.push_str(", world");
+    file.commit_with(txn_id, Durability::None).await?;
+
+    assert!(!tmp.path().join(SIDECAR).exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checksum_read_error() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    {
+        let txn_id = txn_ids.next();
+        let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options()).await?;
+        root.create_file(txn_id, id("text"), Text::from("hello"))
+            .await?;
+
+        root.commit(txn_id, true).await?;
+    }
+
+    // a checksum which exists but can't be read is an error, not a missing checksum
+    std::fs::remove_file(tmp.path().join(SIDECAR))?;
+    std::fs::create_dir(tmp.path().join(SIDECAR))?;
+
+    let txn_id = txn_ids.next();
+    assert!(matches!(
+        Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options()).await,
+        Err(Error::IO(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_overlapping_checksums() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    // the background sync of each commit can overlap with the sync of the next one,
+    // and both record the checksum of the same file
+    for i in 0..16 {
+        let txn_id = txn_ids.next();
+        file.write::<Text>(txn_id).await?.0.push('!');
+
+        if i % 2 == 0 {
+            file.commit_with(txn_id, Durability::Async).await?;
+        } else {
+            file.commit_with(txn_id, Durability::Sync).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,317 @@
+mod common;
+
+use std::time::{Duration, SystemTime};
+
+use common::*;
+use futures::future::try_join_all;
+use txfs::{Dir, DirOptions, Durability, Error, TxnIdSource, VERSIONS};
+
+#[tokio::test]
+async fn test_group_commit() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().group_commit(Duration::from_millis(20), 2);
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    root.commit(txn_id, true).await?;
+
+    // more commits than fit in one batch, so the last batch is flushed by its window
+    let commits = ["a", "b", "c"].into_iter().map(|name| {
+        let root = root.clone();
+        let txn_id = txn_ids.next();
+
+        async move {
+            root.create_file(txn_id, id(name), Text::from(name)).await?;
+
+            root.commit(txn_id, true).await
+        }
+    });
+
+    tokio::time::timeout(Duration::from_secs(5), try_join_all(commits))
+        .await
+        .expect("group commit")?;
+
+    for name in ["a", "b", "c"] {
+        let contents = std::fs::read_to_string(tmp.path().join(name))?;
+        assert_eq!(contents, name);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_durability() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    root.commit(txn_id, true).await?;
+
+    // a commit without waiting for I/O is still synchronized in the background
+    let txn_id = txn_ids.next();
+    root.create_file(txn_id, id("async"), Text::from("async"))
+        .await?;
+    root.commit_with(txn_id, true, Durability::Async).await?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(
+        *root.read_file::<Text>(txn_id, &id("async")).await?,
+        Text::from("async")
+    );
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while std::fs::read_to_string(tmp.path().join("async")).ok() != Some("async".into()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("background sync");
+
+    // a commit without any I/O is readable, but only written to disk by a later commit
+    root.create_file(txn_id, id("none"), Text::from("none"))
+        .await?;
+    root.commit_with(txn_id, true, Durability::None).await?;
+    assert!(!tmp.path().join("none").exists());
+
+    let txn_id = txn_ids.next();
+    assert_eq!(
+        *root.read_file::<Text>(txn_id, &id("none")).await?,
+        Text::from("none")
+    );
+
+    let file = root.get_file(txn_id, &id("none")).await?.expect("file");
+    file.write::<Text>(txn_id).await?.0.push('!');
+    root.commit_with(txn_id, true, Durability::Sync).await?;
+
+    assert_eq!(std::fs::read_to_string(tmp.path().join("none"))?, "none!");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_background_sync_error() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let sub_dir = root.create_dir(txn_id, id("sub")).await?;
+    let file = sub_dir
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    // replace the canonical dir with a file, so that it can't be synchronized
+    std::fs::remove_dir_all(tmp.path().join("sub"))?;
+    std::fs::write(tmp.path().join("sub"), "not a dir")?;
+
+    let txn_id = txn_ids.next();
+    file.write::<Text>(txn_id).await?.0.push('!');
+    root.commit_with(txn_id, true, Durability::Async).await?;
+
+    // the failure is reported by the next commit, rather than dropped
+    let result = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let txn_id = txn_ids.next();
+            if let Err(cause) = root.commit(txn_id, true).await {
+                break cause;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("background sync error");
+
+    assert!(matches!(result, Error::IO(_)));
+
+    // and only once
+    root.commit(txn_ids.next(), true).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_group_commit_abandoned() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().group_commit(Duration::from_millis(100), 8);
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    root.commit(txn_id, true).await?;
+
+    let first = txn_ids.next();
+    let second = txn_ids.next();
+
+    root.create_file(first, id("first"), Text::from("first"))
+        .await?;
+
+    root.create_file(second, id("second"), Text::from("second"))
+        .await?;
+
+    // the first commit in the batch stops waiting before the batch is flushed
+    let abandoned = tokio::time::timeout(Duration::from_millis(10), root.commit(first, true));
+    assert!(abandoned.await.is_err());
+
+    // but the rest of the batch still completes
+    tokio::time::timeout(Duration::from_secs(5), root.commit(second, true))
+        .await
+        .expect("group commit")?;
+
+    for name in ["first", "second"] {
+        let contents = std::fs::read_to_string(tmp.path().join(name))?;
+        assert_eq!(contents, name);
+    }
+
+    Ok(())
+}
+
+// return `true` if the version of the file with the given `name` at `txn_id` is in the cache
+#[tokio::test(flavor = "multi_thread")]
+async fn test_later_commit_after_dir_commit() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let names = (0..16).map(|i| format!("file{i}")).collect::<Vec<_>>();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+
+    for name in &names {
+        root.create_file(txn_id, id(name), Text::from("created"))
+            .await?;
+    }
+
+    root.commit(txn_id, true).await?;
+
+    for _ in 0..8 {
+        let earlier = txn_ids.next();
+        let later = txn_ids.next();
+
+        for name in &names {
+            root.write_file::<Text>(earlier, &id(name)).await?.0 = "earlier".to_string();
+        }
+
+        // each later write waits for the earlier commit, then commits its file right away
+        let mut writes = Vec::with_capacity(names.len());
+        for name in &names {
+            let file = root.get_file(later, &id(name)).await?.expect("file");
+
+            writes.push(tokio::spawn(async move {
+                file.write::<Text>(later).await?.0 = "later".to_string();
+                file.commit(later).await
+            }));
+        }
+
+        root.commit(earlier, true).await?;
+
+        for write in writes {
+            write.await.expect("write")?;
+        }
+
+        // so the earlier version must have been copied before the later one
+        for name in &names {
+            let contents = std::fs::read_to_string(tmp.path().join(name))?;
+            assert_eq!(contents, "later");
+        }
+    }
+
+    Ok(())
+}
+
+async fn has_version(cache: &freqfs::DirLock<File>, name: &str, txn_id: TxnId) -> bool {
+    let root = cache.read().await;
+    let versions = root.get_dir(VERSIONS).expect("versions").read().await;
+    let file = versions.get_dir(name).expect("file versions").read().await;
+    file.contains(&txn_id.to_string())
+}
+
+#[tokio::test]
+async fn test_discard_unmodified() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let cache = tmp.cache();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, cache.clone()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    // mark the canonical version, to detect whether it's rewritten
+    let canon = tmp.path().join("text");
+    let marked = SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 20);
+    std::fs::File::options()
+        .write(true)
+        .open(&canon)?
+        .set_modified(marked)?;
+
+    // a write guard which is never mutably dereferenced doesn't leave a new version behind
+    let txn_id = txn_ids.next();
+    let version = file.write::<Text>(txn_id).await?;
+    assert_eq!(*version, Text::from("hello"));
+    std::mem::drop(version);
+
+    root.commit(txn_id, true).await?;
+    assert!(!has_version(&cache, "text", txn_id).await);
+
+    // so committing it doesn't rewrite the canonical version
+    assert_eq!(std::fs::metadata(&canon)?.modified()?, marked);
+
+    // nor does rolling back, or finalizing a transaction which was never committed
+    for end in ["rollback", "finalize"] {
+        let txn_id = txn_ids.next();
+        std::mem::drop(file.write::<Text>(txn_id).await?);
+        assert!(has_version(&cache, "text", txn_id).await);
+
+        if end == "rollback" {
+            root.rollback(txn_id, true).await;
+        } else {
+            file.finalize(txn_id).await;
+        }
+
+        assert!(!has_version(&cache, "text", txn_id).await);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drop_unmodified() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    let txn_id = txn_ids.next();
+    let version = file.write::<Text>(txn_id).await?;
+    assert!(root.memory_usage(txn_id) > 0);
+
+    // dropping a write guard which was never mutably dereferenced releases its charge
+    std::mem::drop(version);
+    assert_eq!(root.memory_usage(txn_id), 0);
+
+    // a later write guard in the same transaction replaces the version and keeps it
+    file.write::<Text>(txn_id).await?.0.push_str(", world");
+    assert!(root.memory_usage(txn_id) > 0);
+    root.commit(txn_id, true).await?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(
+        *file.read::<Text>(txn_id).await?,
+        Text::from("hello, world")
+    );
+
+    Ok(())
+}
@@ -0,0 +1,111 @@
+#![allow(dead_code)]
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use freqfs::{Cache, DirLock, FileLoad, FileSave};
+use get_size::GetSize;
+use rand::Rng;
+use safecast::as_type;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use txfs::{CounterTxnId, Id};
+
+pub type TxnId = CounterTxnId;
+
+/// A file type which doesn't depend on the "stream" feature
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Text(pub String);
+
+impl From<&str> for Text {
+    fn from(text: &str) -> Self {
+        Self(text.to_string())
+    }
+}
+
+impl GetSize for Text {
+    fn get_heap_size(&self) -> usize {
+        self.0.get_heap_size()
+    }
+}
+
+#[async_trait]
+impl FileLoad for Text {
+    async fn load(_path: &Path, mut file: fs::File, _: std::fs::Metadata) -> io::Result<Self> {
+        let mut text = String::new();
+        file.read_to_string(&mut text).await?;
+        Ok(Self(text))
+    }
+}
+
+#[async_trait]
+impl<'en> FileSave<'en> for Text {
+    async fn save(&'en self, file: &mut fs::File) -> io::Result<u64> {
+        file.write_all(self.0.as_bytes()).await?;
+        Ok(self.0.len() as u64)
+    }
+}
+
+#[derive(Clone)]
+pub enum File {
+    Text(Text),
+    #[cfg(feature = "stream")]
+    Bin(Vec<u8>),
+}
+
+#[async_trait]
+impl<'en> FileSave<'en> for File {
+    async fn save(&'en self, file: &mut fs::File) -> io::Result<u64> {
+        match self {
+            Self::Text(text) => text.save(file).await,
+            #[cfg(feature = "stream")]
+            Self::Bin(bytes) => bytes.save(file).await,
+        }
+    }
+}
+
+as_type!(File, Text, Text);
+#[cfg(feature = "stream")]
+as_type!(File, Bin, Vec<u8>);
+
+/// A temporary directory which is removed when dropped
+pub struct TmpDir {
+    path: PathBuf,
+}
+
+impl TmpDir {
+    pub fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        loop {
+            let rand: u32 = rng.gen();
+            let path = std::env::temp_dir().join(format!("test_txfs_{}", rand));
+            if !path.exists() {
+                std::fs::create_dir(&path).expect("tmp dir");
+                break Self { path };
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load a new cache over this directory
+    pub fn cache(&self) -> DirLock<File> {
+        Cache::new(1 << 20, None)
+            .load(self.path.clone())
+            .expect("cache")
+    }
+}
+
+impl Drop for TmpDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn id(name: &str) -> Id {
+    name.parse().expect("id")
+}
@@ -0,0 +1,370 @@
+mod common;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use common::*;
+use txfs::{Dir, DirOptions, Error, TxnIdSource, LOCK, VERSIONS};
+
+#[tokio::test]
+async fn test_try_create_file_exists() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root.try_create_file(txn_id, id("text"), Text::from("first"))?;
+
+    assert!(matches!(
+        root.try_create_file(txn_id, id("text"), Text::from("second")),
+        Err(Error::AlreadyExists(_))
+    ));
+
+    // the failed attempt must not overwrite the existing version
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("first"));
+
+    root.commit(txn_id, true).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_try_delete() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    root.create_file(txn_id, id("one"), Text::from("one"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+    root.try_create_file(earlier, id("two"), Text::from("two"))?;
+
+    // an entry with a pending write at another transaction can't be deleted synchronously
+    assert!(matches!(
+        root.try_delete(later, id("two")),
+        Err(Error::WouldBlock(_))
+    ));
+
+    // but any other entry can
+    assert!(root.try_delete(later, id("one"))?);
+    assert!(!root.try_delete(later, id("three"))?);
+
+    root.commit(earlier, true).await?;
+
+    assert!(root.try_delete(later, id("two"))?);
+    root.commit(later, true).await?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(root.file_names(txn_id).await?.count(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_point_lookup_isolation() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    root.commit(txn_id, true).await?;
+
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+
+    // a later point lookup only records a read of the name it looked up...
+    assert!(root.get_file(later, &id("one")).await?.is_none());
+
+    // ...so an earlier transaction can still create an entry with any other name
+    root.try_create_file(earlier, id("two"), Text::from("two"))?;
+
+    // but not the name which was looked up, since the later lookup should have found it
+    assert!(matches!(
+        root.try_create_file(earlier, id("one"), Text::from("one")),
+        Err(Error::Conflict(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_iter_snapshot() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    root.create_file(txn_id, id("one"), Text::from("one"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    let names = |txn_id| -> Result<Vec<String>, Error> {
+        let entries = root.iter_snapshot(txn_id)?;
+        Ok(entries.map(|(name, _entry)| name.to_string()).collect())
+    };
+
+    // a snapshot only lists committed entries, not even those pending at its own transaction
+    let first = txn_ids.next();
+    root.create_file(first, id("two"), Text::from("two"))
+        .await?;
+    assert_eq!(names(first)?, ["one"]);
+
+    // and it doesn't block a concurrent change at an earlier transaction
+    let second = txn_ids.next();
+    assert_eq!(names(second)?, ["one"]);
+
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        root.create_file(first, id("three"), Text::from("three")),
+    )
+    .await
+    .expect("create file")?;
+
+    root.delete(first, id("one")).await?;
+    root.commit(first, true).await?;
+
+    // committed changes are visible to a snapshot as of a later transaction
+    assert_eq!(names(first)?, ["three", "two"]);
+    assert_eq!(names(second)?, ["three", "two"]);
+
+    // even after they're finalized
+    root.finalize(first).await;
+    assert_eq!(names(second)?, ["three", "two"]);
+
+    root.finalize(second).await;
+    assert!(matches!(names(first), Err(Error::Outdated(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lazy_load() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    std::fs::create_dir(tmp.path().join("sub"))?;
+    std::fs::write(tmp.path().join("one"), "one")?;
+    std::fs::write(tmp.path().join("sub").join("two"), "two")?;
+
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().lazy_load(true);
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    root.commit(txn_id, true).await?;
+
+    let txn_id = txn_ids.next();
+    let one = root.get_file(txn_id, &id("one")).await?.expect("file");
+
+    // an entry which hasn't been loaded yet can't be read synchronously...
+    assert!(matches!(
+        one.try_read::<Text>(txn_id),
+        Err(Error::WouldBlock(_))
+    ));
+
+    // ...but it's loaded the first time it's read
+    assert_eq!(*one.read::<Text>(txn_id).await?, Text::from("one"));
+    assert_eq!(*one.try_read::<Text>(txn_id)?, Text::from("one"));
+
+    let sub_dir = root.get_dir(txn_id, &id("sub")).await?.expect("dir");
+    assert_eq!(
+        *sub_dir.read_file::<Text>(txn_id, &id("two")).await?,
+        Text::from("two")
+    );
+
+    // and a lazily loaded file can be written and committed like any other
+    let txn_id = txn_ids.next();
+    sub_dir
+        .get_file(txn_id, &id("two"))
+        .await?
+        .expect("file")
+        .write::<Text>(txn_id)
+        .await?
+        .0
+        .push('!');
+
+    root.commit(txn_id, true).await?;
+    assert_eq!(
+        std::fs::read_to_string(tmp.path().join("sub").join("two"))?,
+        "two!"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_validate_names() -> Result<(), Error> {
+    fn is_valid(name: &txfs::Id) -> bool {
+        !name.as_str().contains('_')
+    }
+
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    // an invalid name in a nested dir on disk fails the whole load
+    let tmp = TmpDir::new();
+    std::fs::create_dir(tmp.path().join("sub"))?;
+    std::fs::write(tmp.path().join("valid"), "valid")?;
+    std::fs::write(tmp.path().join("sub").join("in_valid"), "invalid")?;
+
+    let options = DirOptions::default().validate_names(is_valid);
+    let result = Dir::<TxnId, File>::load_with(txn_ids.next(), tmp.cache(), options).await;
+    assert!(matches!(result, Err(Error::InvalidName(_))));
+
+    std::fs::remove_file(tmp.path().join("sub").join("in_valid"))?;
+
+    // as does creating an entry with an invalid name
+    let txn_id = txn_ids.next();
+    let options = DirOptions::default().validate_names(is_valid);
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    let sub_dir = root.get_dir(txn_id, &id("sub")).await?.expect("dir");
+
+    assert!(matches!(
+        root.create_file(txn_id, id("in_valid"), Text::from("invalid"))
+            .await,
+        Err(Error::InvalidName(_))
+    ));
+
+    assert!(matches!(
+        sub_dir.create_dir(txn_id, id("in_valid")).await,
+        Err(Error::InvalidName(_))
+    ));
+
+    sub_dir
+        .create_file(txn_id, id("valid"), Text::from("valid"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    assert!(!tmp.path().join("in_valid").exists());
+    assert!(!tmp.path().join("sub").join("in_valid").exists());
+    assert!(tmp.path().join("sub").join("valid").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_versions_root() -> Result<(), Error> {
+    let canon = TmpDir::new();
+    let versions = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let cache = freqfs::Cache::<File>::new(1 << 20, None);
+    let versions_root = cache.clone().load(versions.path().to_path_buf())?;
+    let options = DirOptions::default().versions_root(versions_root);
+
+    let txn_id = txn_ids.next();
+    let root = Dir::load_with(txn_id, cache.load(canon.path().to_path_buf())?, options).await?;
+    let sub_dir = root.create_dir(txn_id, id("sub")).await?;
+
+    let file = sub_dir
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    // streaming a version writes it to the filesystem where it's cached
+    std::mem::drop(file.reader(txn_id).await?);
+
+    // which is under the versions root, at the same path as in the canonical dir
+    let version = txn_id.to_string();
+    assert!(versions.path().join("sub/text").join(&version).is_file());
+    assert!(!canon.path().join(VERSIONS).exists());
+    assert!(!canon.path().join("sub").join(VERSIONS).exists());
+
+    root.commit(txn_id, true).await?;
+
+    let contents = std::fs::read_to_string(canon.path().join("sub").join("text"))?;
+    assert_eq!(contents, "hello");
+
+    root.finalize(txn_id).await;
+
+    // the canonical dir holds nothing but its entries and its lock file, in the cache...
+    let cached = root.clone().into_inner();
+    assert!(!cached.read().await.contains(VERSIONS));
+
+    // ...and on disk
+    let mut names = std::fs::read_dir(canon.path())?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    names.sort();
+    assert_eq!(names, [LOCK, "sub"]);
+
+    let names = std::fs::read_dir(canon.path().join("sub"))?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    assert_eq!(names, ["text"]);
+
+    Ok(())
+}
+
+type Walk = Pin<Box<dyn Future<Output = Result<Vec<(String, String)>, Error>> + Send>>;
+
+// collect the path and contents of every file under `dir`, recursively
+fn walk(dir: Dir<TxnId, File>, txn_id: TxnId, prefix: String) -> Walk {
+    Box::pin(async move {
+        let mut contents = Vec::new();
+
+        for name in dir.file_names(txn_id).await? {
+            let file = dir.get_file(txn_id, &name).await?.expect("file");
+            let text = file.read::<Text>(txn_id).await?;
+            contents.push((format!("{prefix}/{}", *name), text.0.clone()));
+        }
+
+        for name in dir.dir_names(txn_id).await? {
+            let sub_dir = dir.get_dir(txn_id, &name).await?.expect("dir").clone();
+            contents.extend(walk(sub_dir, txn_id, format!("{prefix}/{}", *name)).await?);
+        }
+
+        contents.sort();
+        Ok(contents)
+    })
+}
+
+#[tokio::test]
+async fn test_load_concurrency() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let expected = {
+        let txn_id = txn_ids.next();
+        let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+        let mut level = vec![root.clone()];
+
+        for depth in 0..3 {
+            let mut next = Vec::new();
+
+            for dir in level {
+                for i in 0..3 {
+                    let name = format!("file{depth}{i}");
+                    dir.create_file(txn_id, id(&name), Text::from(name.as_str()))
+                        .await?;
+
+                    next.push(dir.create_dir(txn_id, id(&format!("dir{i}"))).await?);
+                }
+            }
+
+            level = next;
+        }
+
+        root.commit(txn_id, true).await?;
+        walk(root, txn_id, String::new()).await?
+    };
+
+    assert_eq!(expected.len(), 3 + 9 + 27);
+
+    // loading one entry at a time across the whole tree must not deadlock,
+    // and loading concurrently must produce the same tree as loading sequentially
+    for max_concurrency in [1, 4] {
+        let options = DirOptions::default().load_concurrency(max_concurrency);
+        let txn_id = txn_ids.next();
+        let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+        assert_eq!(walk(root, txn_id, String::new()).await?, expected);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use std::time::Duration;
+
+use common::*;
+use txfs::{Dir, DirOptions, Error, TxnIdSource};
+
+#[tokio::test]
+async fn test_retryable_errors() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().lock_timeout(Duration::from_millis(50));
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+
+    // a write at an earlier transaction than a read conflicts with it
+    file.read::<Text>(later).await?;
+    let conflict = file.write::<Text>(earlier).await.map(|_| ()).unwrap_err();
+    assert!(matches!(conflict, Error::Conflict(_)));
+    assert!(conflict.is_conflict());
+    assert!(conflict.is_retryable());
+
+    // a later transaction can wait for a pending write, but only until its lock times out
+    let version = file.write::<Text>(later).await?;
+
+    let timeout = file
+        .read::<Text>(txn_ids.next())
+        .await
+        .map(|_| ())
+        .unwrap_err();
+    assert!(matches!(timeout, Error::Timeout(_)));
+    assert!(!timeout.is_conflict());
+    assert!(timeout.is_retryable());
+
+    // or not at all, if it tries to lock the file synchronously
+    let would_block = file.try_read::<Text>(later).map(|_| ()).unwrap_err();
+    assert!(matches!(would_block, Error::WouldBlock(_)));
+    assert!(!would_block.is_conflict());
+    assert!(would_block.is_retryable());
+
+    std::mem::drop(version);
+    root.rollback(earlier, true).await;
+    root.commit(later, true).await?;
+    root.finalize(later).await;
+
+    // a transaction which was already finalized is outdated
+    let outdated = root.iter_snapshot(earlier).map(|_| ()).unwrap_err();
+    assert!(matches!(outdated, Error::Outdated(_)));
+    assert!(outdated.is_conflict());
+    assert!(outdated.is_retryable());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_permanent_errors() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    root.create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+    root.create_dir(txn_id, id("sub")).await?;
+
+    // retrying an invalid operation in a new transaction won't make it succeed
+    let errors = [
+        root.create_dir(txn_id, id("text"))
+            .await
+            .map(|_| ())
+            .unwrap_err(),
+        root.get_dir(txn_id, &id("text"))
+            .await
+            .map(|_| ())
+            .unwrap_err(),
+        root.get_file(txn_id, &id("sub"))
+            .await
+            .map(|_| ())
+            .unwrap_err(),
+        Error::from(std::io::Error::other("disk failure")),
+        "not a transaction ID".parse::<TxnId>().unwrap_err(),
+    ];
+
+    assert!(matches!(errors[0], Error::AlreadyExists(_)));
+    assert!(matches!(errors[1], Error::NotADirectory(_)));
+    assert!(matches!(errors[2], Error::NotAFile(_)));
+    assert!(matches!(errors[3], Error::IO(_)));
+    assert!(matches!(errors[4], Error::Parse(_)));
+
+    for error in errors {
+        assert!(!error.is_conflict(), "{error} is not a conflict");
+        assert!(!error.is_retryable(), "{error} is not retryable");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::*;
+use txfs::{Dir, Error, ExternalChangeKind, TxnIdSource};
+
+type TxnFile = txfs::File<TxnId, File>;
+
+async fn setup(
+    tmp: &TmpDir,
+    txn_ids: &TxnIdSource<TxnId>,
+) -> Result<(Dir<TxnId, File>, TxnFile), Error> {
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    Ok((root, file))
+}
+
+#[tokio::test]
+async fn test_verify_external() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&tmp, &txn_ids).await?;
+    let canon = tmp.path().join("text");
+
+    // a file committed by the transactional filesystem itself is not reported
+    assert!(root.verify_external(txn_ids.next()).await?.is_empty());
+
+    let txn_id = txn_ids.next();
+    file.write::<Text>(txn_id).await?.0.push_str(", world");
+    root.commit(txn_id, true).await?;
+    assert!(root.verify_external(txn_ids.next()).await?.is_empty());
+
+    std::fs::write(&canon, "changed externally")?;
+
+    let changes = root.verify_external(txn_ids.next()).await?;
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind(), ExternalChangeKind::Modified);
+    assert_eq!(changes[0].path(), canon);
+
+    std::fs::remove_file(&canon)?;
+
+    let changes = root.verify_external(txn_ids.next()).await?;
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind(), ExternalChangeKind::Deleted);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reload() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&tmp, &txn_ids).await?;
+    let canon = tmp.path().join("text");
+
+    std::fs::write(&canon, "changed externally")?;
+
+    // accept the change
+    let txn_id = txn_ids.next();
+    let changes = root.verify_external(txn_id).await?;
+    changes[0].file().reload::<Text>(txn_id).await?;
+    assert_eq!(
+        *file.read::<Text>(txn_id).await?,
+        Text::from("changed externally")
+    );
+
+    root.commit(txn_id, true).await?;
+    assert!(root.verify_external(txn_ids.next()).await?.is_empty());
+
+    // a deleted file can't be reloaded
+    std::fs::remove_file(&canon)?;
+    assert!(matches!(
+        file.reload::<Text>(txn_ids.next()).await,
+        Err(Error::NotFound(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&tmp, &txn_ids).await?;
+    let canon = tmp.path().join("text");
+
+    // revert a modification
+    std::fs::write(&canon, "changed externally")?;
+
+    let txn_id = txn_ids.next();
+    file.restore::<Text>(txn_id).await?;
+    root.commit(txn_id, true).await?;
+
+    assert_eq!(std::fs::read_to_string(&canon)?, "hello");
+    assert!(root.verify_external(txn_ids.next()).await?.is_empty());
+
+    // and a deletion
+    std::fs::remove_file(&canon)?;
+
+    let txn_id = txn_ids.next();
+    file.restore::<Text>(txn_id).await?;
+    root.commit(txn_id, true).await?;
+
+    assert_eq!(std::fs::read_to_string(&canon)?, "hello");
+    assert!(root.verify_external(txn_ids.next()).await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_watch_external() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = Arc::new(TxnIdSource::<TxnId>::default());
+    let (root, _file) = setup(&tmp, &txn_ids).await?;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let watcher = root.watch_external(
+        Duration::from_millis(10),
+        {
+            let txn_ids = txn_ids.clone();
+            move || txn_ids.next()
+        },
+        move |result| {
+            let _ = tx.send(result);
+        },
+    );
+
+    std::fs::write(tmp.path().join("text"), "changed externally")?;
+
+    let changes = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("external change")
+        .expect("watcher")?;
+
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind(), ExternalChangeKind::Modified);
+
+    // the watcher stops when dropped
+    std::mem::drop(watcher);
+    let stopped = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await;
+    assert!(stopped.expect("stop watching").is_none());
+
+    Ok(())
+}
@@ -0,0 +1,446 @@
+mod common;
+
+use common::*;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use txfs::{Dir, DirOptions, Error, TxnIdSource, VERSIONS};
+
+#[tokio::test]
+async fn test_read_range_past_end() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(file.read_range(txn_id, 0..u64::MAX).await?, b"hello");
+    assert_eq!(file.read_range(txn_id, 1..3).await?, b"el");
+    assert_eq!(file.read_range(txn_id, 3..10).await?, b"lo");
+    assert!(file.read_range(txn_id, 5..10).await?.is_empty());
+    assert!(file.read_range(txn_id, 10..u64::MAX).await?.is_empty());
+
+    Ok(())
+}
+
+// list the names of the files on disk under `path`, recursively
+fn files_under(path: &std::path::Path) -> Vec<String> {
+    let mut names = Vec::new();
+
+    for entry in std::fs::read_dir(path).expect("dir") {
+        let entry = entry.expect("dir entry");
+
+        if entry.path().is_dir() {
+            names.extend(files_under(&entry.path()));
+        } else {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+
+    names
+}
+
+#[tokio::test]
+async fn test_read_range_writes_nothing() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    std::fs::write(tmp.path().join("text"), "hello")?;
+
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().lazy_load(true);
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    root.commit(txn_id, true).await?;
+    let before = files_under(tmp.path());
+
+    // a lazily loaded file is read from its canonical version without loading it
+    let txn_id = txn_ids.next();
+    let file = root.get_file(txn_id, &id("text")).await?.expect("file");
+    assert_eq!(file.read_range(txn_id, 1..3).await?, b"el");
+    assert_eq!(files_under(tmp.path()), before);
+
+    // a version which is only in the cache can't be read without writing it first
+    file.overwrite(txn_id, Text::from("hi")).await?;
+    assert!(matches!(
+        file.read_range(txn_id, 0..2).await,
+        Err(Error::WouldBlock(_))
+    ));
+
+    root.commit(txn_id, true).await?;
+    let before = files_under(tmp.path());
+
+    // but once it's committed, it's read from its canonical version
+    let txn_id = txn_ids.next();
+    assert_eq!(file.read_range(txn_id, 0..2).await?, b"hi");
+    assert_eq!(files_under(tmp.path()), before);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    // a writer starts with the current contents
+    let txn_id = txn_ids.next();
+    let mut writer = file.writer(txn_id).await?;
+    writer.seek(std::io::SeekFrom::End(0)).await?;
+    writer.write_all(b", world").await?;
+
+    // and stages its changes outside of the versions dir
+    let versions = tmp.path().join(VERSIONS).join("text");
+    assert!(files_under(&versions)
+        .iter()
+        .all(|name| !name.ends_with(".partial")));
+
+    // in a dir which the cache doesn't list
+    let cache = root.clone().into_inner();
+    let cached = cache
+        .read()
+        .await
+        .get_dir(VERSIONS)
+        .expect("versions")
+        .clone();
+    assert!(cached
+        .read()
+        .await
+        .names()
+        .all(|name| !name.starts_with('.')));
+
+    writer.finish().await?;
+
+    assert_eq!(
+        *file.read::<Text>(txn_id).await?,
+        Text::from("hello, world")
+    );
+
+    let mut contents = String::new();
+    let mut reader = file.reader(txn_id).await?;
+    reader.read_to_string(&mut contents).await?;
+    assert_eq!(contents, "hello, world");
+    std::mem::drop(reader);
+
+    root.commit(txn_id, true).await?;
+    assert_eq!(
+        std::fs::read_to_string(tmp.path().join("text"))?,
+        "hello, world"
+    );
+
+    // a writer can also replace the current contents with shorter ones
+    let txn_id = txn_ids.next();
+    let mut writer = file.writer(txn_id).await?;
+    writer.set_len(0).await?;
+    writer.write_all(b"bye").await?;
+    writer.finish().await?;
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("bye"));
+
+    root.commit(txn_id, true).await?;
+    assert_eq!(std::fs::read_to_string(tmp.path().join("text"))?, "bye");
+
+    // every staged file is removed once it's no longer needed
+    assert!(files_under(tmp.path())
+        .iter()
+        .all(|name| !name.ends_with(".partial")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drop_writer() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    // a writer which is dropped without finishing leaves no trace
+    let txn_id = txn_ids.next();
+    let mut writer = file.writer(txn_id).await?;
+    writer.write_all(b"HELLO").await?;
+    std::mem::drop(writer);
+
+    assert_eq!(root.memory_usage(txn_id), 0);
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("hello"));
+    assert!(files_under(tmp.path())
+        .iter()
+        .all(|name| !name.ends_with(".partial")));
+
+    // and releases its lock
+    let mut writer = file.writer(txn_id).await?;
+    writer.write_all(b"HELLO").await?;
+    writer.finish().await?;
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("HELLO"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_staged_after_crash() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    {
+        let txn_id = txn_ids.next();
+        let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+        let file = root
+            .create_file(txn_id, id("text"), Text::from("hello"))
+            .await?;
+
+        root.commit(txn_id, true).await?;
+        std::mem::drop(file);
+    }
+
+    // e.g. a process which crashed while streaming a new version
+    let staging = tmp.path().join(VERSIONS).join(".staging");
+    std::fs::create_dir_all(&staging)?;
+    std::fs::write(staging.join("00000000000000000002.0.partial"), "HELLO")?;
+
+    // loading the same root again clears the staged file, from the cache as well as the disk
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    assert!(files_under(tmp.path())
+        .iter()
+        .all(|name| !name.ends_with(".partial")));
+
+    let cache = root.clone().into_inner();
+    let cached = cache
+        .read()
+        .await
+        .get_dir(VERSIONS)
+        .expect("versions")
+        .clone();
+    assert!(cached
+        .read()
+        .await
+        .names()
+        .all(|name| !name.starts_with('.')));
+
+    assert_eq!(
+        *root.read_file::<Text>(txn_id, &id("text")).await?,
+        Text::from("hello")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_try_lock() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    // with no conflicting lock held, synchronous locking succeeds
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+    assert_eq!(*file.try_read::<Text>(earlier)?, Text::from("hello"));
+    file.try_write::<Text>(earlier)?.0.push('!');
+    assert_eq!(*file.try_read::<Text>(earlier)?, Text::from("hello!"));
+
+    // but not while another transaction holds a conflicting lock
+    assert!(matches!(
+        file.try_read::<Text>(later),
+        Err(Error::WouldBlock(_))
+    ));
+    assert!(matches!(
+        file.try_write::<Text>(later),
+        Err(Error::WouldBlock(_))
+    ));
+
+    root.commit(earlier, true).await?;
+
+    assert_eq!(*file.try_read::<Text>(later)?, Text::from("hello!"));
+    file.try_write::<Text>(later)?.0.push('?');
+    assert_eq!(*file.try_read::<Text>(later)?, Text::from("hello!?"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upgrade() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    // a guard which only holds a read lock can't be upgraded without releasing it
+    let txn_id = txn_ids.next();
+    let version = file.read::<Text>(txn_id).await?;
+    assert!(matches!(
+        version.upgrade().await,
+        Err(Error::NotUpgradable(_))
+    ));
+
+    // but a failed upgrade releases the read lock, so the file can still be written
+    file.write::<Text>(txn_id).await?;
+    root.rollback(txn_id, true).await;
+
+    // an upgradable read guard holds the write lock without creating a new version...
+    let txn_id = txn_ids.next();
+    let version = file.read_upgradable::<Text>(txn_id).await?;
+    assert_eq!(*version, Text::from("hello"));
+    assert_eq!(root.memory_usage(txn_id), 0);
+
+    // ...until it's upgraded
+    let mut version = version.upgrade().await?;
+    version.0.push('!');
+    std::mem::drop(version);
+
+    assert!(root.memory_usage(txn_id) > 0);
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("hello!"));
+    root.rollback(txn_id, true).await;
+
+    // a downgraded write guard still holds the write lock
+    let txn_id = txn_ids.next();
+    let version = file.write::<Text>(txn_id).await?.downgrade().await?;
+    assert_eq!(*version, Text::from("hello"));
+
+    let mut version = version.upgrade().await?;
+    version.0.push_str(", world");
+    std::mem::drop(version);
+
+    assert_eq!(
+        *file.read::<Text>(txn_id).await?,
+        Text::from("hello, world")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_overwrite() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    let txn_id = txn_ids.next();
+    file.overwrite(txn_id, Text::from("hi")).await?;
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("hi"));
+
+    // overwriting again at the same transaction replaces the new version
+    root.overwrite_file(txn_id, &id("text"), Text::from("hey"))
+        .await?;
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("hey"));
+
+    root.commit(txn_id, true).await?;
+
+    // an overwrite is subject to the same conflict checks as a write
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+    assert_eq!(*file.read::<Text>(later).await?, Text::from("hey"));
+
+    let result = file.overwrite(earlier, Text::from("too late")).await;
+    assert!(result.unwrap_err().is_conflict());
+
+    assert!(matches!(
+        root.overwrite_file(later, &id("missing"), Text::from("nothing"))
+            .await,
+        Err(Error::NotFound(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_overwrite_after_read() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    // a transaction which has read a file can still overwrite it, once the guard is released
+    let txn_id = txn_ids.next();
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("hello"));
+
+    file.overwrite(txn_id, Text::from("hi")).await?;
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("hi"));
+    assert!(root.memory_usage(txn_id) > 0);
+
+    root.commit(txn_id, true).await?;
+    assert_eq!(root.memory_usage(txn_id), 0);
+    assert_eq!(std::fs::read_to_string(tmp.path().join("text"))?, "hi");
+
+    Ok(())
+}
+
+// list the versions of the file with the given `name` in the cache
+async fn versions(cache: &freqfs::DirLock<File>, name: &str) -> Vec<String> {
+    let root = cache.read().await;
+    let versions = root.get_dir(VERSIONS).expect("versions").read().await;
+    let file = versions.get_dir(name).expect("file versions").read().await;
+    file.names().cloned().collect()
+}
+
+#[tokio::test]
+async fn test_finalize_last_modified() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let cache = tmp.cache();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let created = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(created, cache.clone()).await?;
+    let file = root
+        .create_file(created, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(created, true).await?;
+
+    let modified = txn_ids.next();
+    file.write::<Text>(modified).await?.0.push_str(", world");
+    root.commit(modified, true).await?;
+
+    // finalizing the transaction which last modified a file deletes every earlier version,
+    // but keeps the version written at that transaction, which is still the current version
+    file.finalize(modified).await;
+    assert_eq!(versions(&cache, "text").await, [modified.to_string()]);
+
+    assert_eq!(
+        *file.read::<Text>(txn_ids.next()).await?,
+        Text::from("hello, world")
+    );
+
+    Ok(())
+}
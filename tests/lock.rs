@@ -0,0 +1,368 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use common::*;
+use futures::TryFutureExt;
+use txfs::{Dir, DirOptions, Error, TxnIdSource, LOCK};
+
+const TIMEOUT: Duration = Duration::from_millis(50);
+
+#[tokio::test]
+async fn test_lock_timeout() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().lock_timeout(TIMEOUT);
+
+    let txn_one = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_one, tmp.cache(), options).await?;
+    let file = root
+        .create_file(txn_one, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_one, true).await?;
+
+    let txn_two = txn_ids.next();
+    let txn_three = txn_ids.next();
+
+    // a later transaction waits for a pending write in an earlier one
+    let mut version = file.write::<Text>(txn_two).await?;
+    version.0.push_str(", world");
+
+    assert!(matches!(
+        file.read::<Text>(txn_three).await,
+        Err(Error::Timeout(_))
+    ));
+
+    // so does another task in the same transaction, waiting on the version in the cache
+    assert!(matches!(
+        file.read::<Text>(txn_two).await,
+        Err(Error::Timeout(_))
+    ));
+
+    std::mem::drop(version);
+    assert_eq!(
+        *file.read::<Text>(txn_two).await?,
+        Text::from("hello, world")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_finalize_keeps_root_lock() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+    root.commit(txn_id, true).await?;
+
+    // finalizing an empty root must not delete it, nor the lock file inside it
+    root.finalize(txn_id).await;
+    assert!(tmp.path().join(LOCK).exists());
+
+    assert!(matches!(
+        Dir::<TxnId, File>::load(txn_ids.next(), tmp.cache()).await,
+        Err(Error::Locked(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exclusive_lock() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    let writer = Dir::<TxnId, File>::load(txn_ids.next(), tmp.cache()).await?;
+
+    assert!(matches!(
+        Dir::<TxnId, File>::load(txn_ids.next(), tmp.cache()).await,
+        Err(Error::Locked(_))
+    ));
+
+    std::mem::drop(writer);
+
+    Dir::<TxnId, File>::load(txn_ids.next(), tmp.cache()).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exclusive_and_shared_lock() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let read_only = || DirOptions::default().read_only(true);
+
+    // a writer excludes a reader...
+    let writer = Dir::<TxnId, File>::load(txn_ids.next(), tmp.cache()).await?;
+
+    assert!(matches!(
+        Dir::<TxnId, File>::load_with(txn_ids.next(), tmp.cache(), read_only()).await,
+        Err(Error::Locked(_))
+    ));
+
+    std::mem::drop(writer);
+
+    // ...and a reader excludes a writer, now that the lock file exists
+    let reader = Dir::<TxnId, File>::load_with(txn_ids.next(), tmp.cache(), read_only()).await?;
+
+    assert!(matches!(
+        Dir::<TxnId, File>::load(txn_ids.next(), tmp.cache()).await,
+        Err(Error::Locked(_))
+    ));
+
+    std::mem::drop(reader);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shared_lock() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let read_only = || DirOptions::default().read_only(true);
+
+    std::mem::drop(Dir::<TxnId, File>::load(txn_ids.next(), tmp.cache()).await?);
+    assert!(tmp.path().join(LOCK).exists());
+
+    let reader = Dir::<TxnId, File>::load_with(txn_ids.next(), tmp.cache(), read_only()).await?;
+    let other = Dir::<TxnId, File>::load_with(txn_ids.next(), tmp.cache(), read_only()).await?;
+
+    std::mem::drop((reader, other));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_only_unwritable() -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().read_only(true);
+
+    std::fs::write(tmp.path().join("text"), "hello")?;
+
+    // e.g. a snapshot which was never mounted for writing, so there is no lock file
+    let permissions = std::fs::Permissions::from_mode(0o555);
+    std::fs::set_permissions(tmp.path(), permissions)?;
+
+    let txn_id = txn_ids.next();
+    let result = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await;
+
+    let permissions = std::fs::Permissions::from_mode(0o755);
+    std::fs::set_permissions(tmp.path(), permissions)?;
+
+    // the read-only mount succeeds without a lock and without writing anything
+    let root = result?;
+    assert_eq!(
+        *root.read_file::<Text>(txn_id, &id("text")).await?,
+        Text::from("hello")
+    );
+
+    assert!(!tmp.path().join(LOCK).exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_opposite_lock_order() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().lock_timeout(TIMEOUT);
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    let one = root
+        .create_file(txn_id, id("one"), Text::from("one"))
+        .await?;
+    let two = root
+        .create_file(txn_id, id("two"), Text::from("two"))
+        .await?;
+    root.commit(txn_id, true).await?;
+
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+
+    // each transaction locks one file, then tries to lock the other
+    let mut version_one = one.write::<Text>(earlier).await?;
+    let mut version_two = two.write::<Text>(later).await?;
+    version_one.0.push('!');
+    version_two.0.push('!');
+
+    // the earlier transaction can't write a file which a later one already wrote,
+    // so there is never a cycle: at worst the later transaction waits for the earlier one
+    let (earlier_result, later_result) = tokio::time::timeout(Duration::from_secs(5), async {
+        futures::join!(
+            two.write::<Text>(earlier).map_ok(|_version| ()),
+            one.write::<Text>(later).map_ok(|_version| ()),
+        )
+    })
+    .await
+    .expect("deadlock");
+
+    assert!(matches!(earlier_result, Err(Error::Conflict(_))));
+    assert!(matches!(later_result, Err(Error::Timeout(_))));
+
+    // once the earlier transaction is rolled back, the later one can proceed
+    std::mem::drop(version_one);
+    root.rollback(earlier, true).await;
+
+    one.write::<Text>(later).await?.0.push('?');
+    std::mem::drop(version_two);
+    root.commit(later, true).await?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(*one.read::<Text>(txn_id).await?, Text::from("one?"));
+    assert_eq!(*two.read::<Text>(txn_id).await?, Text::from("two!"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_opposite_lock_order_optimistic() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().lock_timeout(TIMEOUT).optimistic(true);
+
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    let one = root
+        .create_file(txn_id, id("one"), Text::from("one"))
+        .await?;
+    let two = root
+        .create_file(txn_id, id("two"), Text::from("two"))
+        .await?;
+    root.commit(txn_id, true).await?;
+
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+
+    // the earlier transaction also creates an entry, which the later commit has to wait for
+    root.create_file(earlier, id("three"), Text::from("three"))
+        .await?;
+
+    // writes take no lock in optimistic mode, so each transaction writes both files
+    one.write::<Text>(earlier).await?.0.push('!');
+    two.write::<Text>(later).await?.0.push('!');
+    two.write::<Text>(earlier).await?.0.push('!');
+    one.write::<Text>(later).await?.0.push('?');
+
+    // and neither commit waits for the other while holding the permission to commit
+    let (later_result, earlier_result) = tokio::time::timeout(Duration::from_secs(5), async {
+        futures::join!(root.commit(later, true), root.commit(earlier, true))
+    })
+    .await
+    .expect("deadlock");
+
+    // the earlier transaction wrote a file which the later one already observed,
+    // and the later one waits for the earlier create until the earlier one is rolled back
+    assert!(matches!(earlier_result, Err(Error::Conflict(_))));
+    assert!(matches!(later_result, Err(Error::Timeout(_))));
+
+    root.rollback(earlier, true).await;
+    root.commit(later, true).await?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(*one.read::<Text>(txn_id).await?, Text::from("one?"));
+    assert_eq!(*two.read::<Text>(txn_id).await?, Text::from("two!"));
+    assert!(!root.contains(txn_id, &id("three")).await?);
+
+    Ok(())
+}
+
+// list the path and contents of every file on disk under `path`, recursively
+fn contents_under(path: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut contents = Vec::new();
+
+    for entry in std::fs::read_dir(path).expect("dir") {
+        let path = entry.expect("dir entry").path();
+
+        if path.is_dir() {
+            contents.push((path.clone(), Vec::new()));
+            contents.extend(contents_under(&path));
+        } else {
+            let file = std::fs::read(&path).expect("file");
+            contents.push((path, file));
+        }
+    }
+
+    contents.sort();
+    contents
+}
+
+#[tokio::test]
+async fn test_read_only_rejects_writes() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+
+    {
+        let txn_id = txn_ids.next();
+        let root = Dir::<TxnId, File>::load(txn_id, tmp.cache()).await?;
+        root.create_file(txn_id, id("text"), Text::from("hello"))
+            .await?;
+        root.create_dir(txn_id, id("sub")).await?;
+        root.commit(txn_id, true).await?;
+    }
+
+    let before = contents_under(tmp.path());
+
+    let options = DirOptions::default().read_only(true);
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    let file = root.get_file(txn_id, &id("text")).await?.expect("file");
+
+    assert!(matches!(
+        root.create_file(txn_id, id("new"), Text::from("new")).await,
+        Err(Error::ReadOnly(_))
+    ));
+    assert!(matches!(
+        root.create_dir(txn_id, id("new")).await,
+        Err(Error::ReadOnly(_))
+    ));
+    assert!(matches!(
+        file.write::<Text>(txn_id).await,
+        Err(Error::ReadOnly(_))
+    ));
+    assert!(matches!(
+        file.overwrite(txn_id, Text::from("hi")).await,
+        Err(Error::ReadOnly(_))
+    ));
+    assert!(matches!(
+        root.delete(txn_id, id("text")).await,
+        Err(Error::ReadOnly(_))
+    ));
+    assert!(matches!(
+        root.delete(txn_id, id("sub")).await,
+        Err(Error::ReadOnly(_))
+    ));
+
+    // a read-only transaction can still read, and commit since it has no changes
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("hello"));
+    file.commit(txn_id).await?;
+    root.commit(txn_id, true).await?;
+    root.finalize(txn_id).await;
+
+    // so a later transaction can read the same listing and contents
+    let txn_id = txn_ids.next();
+    assert_eq!(
+        root.file_names(txn_id)
+            .await?
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>(),
+        vec!["text"]
+    );
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("hello"));
+
+    // or end with a rollback
+    root.rollback(txn_id, true).await;
+    root.finalize(txn_id).await;
+
+    std::mem::drop((file, root));
+    assert_eq!(contents_under(tmp.path()), before);
+
+    Ok(())
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use common::*;
+use get_size::GetSize;
+use txfs::{Dir, DirOptions, Error, TxnIdSource};
+
+const LIMIT: usize = 50;
+
+type TxnFile = txfs::File<TxnId, File>;
+
+async fn setup(
+    tmp: &TmpDir,
+    txn_ids: &TxnIdSource<TxnId>,
+) -> Result<(Dir<TxnId, File>, TxnFile, TxnFile), Error> {
+    let options = DirOptions::default().txn_memory_limit(LIMIT);
+    let root = Dir::<TxnId, File>::load_with(txn_ids.next(), tmp.cache(), options).await?;
+
+    // each transaction is charged for the files it creates, so create them separately
+    let mut files = Vec::with_capacity(2);
+    for name in ["one", "two"] {
+        let txn_id = txn_ids.next();
+        files.push(root.create_file(txn_id, id(name), Text::from(name)).await?);
+        root.commit(txn_id, true).await?;
+    }
+
+    let two = files.pop().expect("two");
+    let one = files.pop().expect("one");
+    Ok((root, one, two))
+}
+
+#[tokio::test]
+async fn test_memory_limit() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, one, two) = setup(&tmp, &txn_ids).await?;
+
+    // a version which is larger than the limit on its own can't be written
+    let txn_id = txn_ids.next();
+    let big = Text("x".repeat(LIMIT));
+    assert!(matches!(
+        one.overwrite(txn_id, big.clone()).await,
+        Err(Error::MemoryLimit(_))
+    ));
+
+    // nor can any version written after a transaction's versions grow past the limit
+    *one.write::<Text>(txn_id).await? = big;
+    assert!(root.memory_usage(txn_id) > LIMIT);
+    assert!(matches!(
+        two.write::<Text>(txn_id).await,
+        Err(Error::MemoryLimit(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_memory_usage() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, one, two) = setup(&tmp, &txn_ids).await?;
+    assert!(root.memory_usage_by_txn().is_empty());
+
+    let first = txn_ids.next();
+    let second = txn_ids.next();
+
+    one.write::<Text>(first).await?.0.push('!');
+    two.write::<Text>(second).await?.0.push_str("!!");
+
+    let one_size = one.read::<Text>(first).await?.get_size();
+    let two_size = two.read::<Text>(second).await?.get_size();
+    assert_eq!(root.memory_usage(first), one_size);
+    assert_eq!(root.memory_usage(second), two_size);
+
+    let mut usage = root.memory_usage_by_txn();
+    usage.sort();
+    assert_eq!(usage, [(first, one_size), (second, two_size)]);
+
+    // the charge of a transaction is released when it's committed or rolled back
+    root.commit(first, true).await?;
+    assert_eq!(root.memory_usage(first), 0);
+
+    root.rollback(second, true).await;
+    assert_eq!(root.memory_usage(second), 0);
+    assert!(root.memory_usage_by_txn().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_inspect_within_limit() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, one, two) = setup(&tmp, &txn_ids).await?;
+
+    // the versions of write guards which only inspect their files aren't charged,
+    // even though together they would exceed the limit
+    let txn_id = txn_ids.next();
+    for _ in 0..2 {
+        for file in [&one, &two] {
+            assert!(file.write::<Text>(txn_id).await?.0.len() == 3);
+        }
+    }
+
+    assert_eq!(root.memory_usage(txn_id), 0);
+
+    two.write::<Text>(txn_id).await?.0.push('!');
+    let size = two.read::<Text>(txn_id).await?.get_size();
+    assert_eq!(root.memory_usage(txn_id), size);
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+mod common;
+
+use std::time::Duration;
+
+use common::*;
+use txfs::{Dir, DirOptions, Error, TxnIdSource};
+
+async fn setup(
+    tmp: &TmpDir,
+    txn_ids: &TxnIdSource<TxnId>,
+) -> Result<(Dir<TxnId, File>, txfs::File<TxnId, File>), Error> {
+    let txn_id = txn_ids.next();
+    let options = DirOptions::default().optimistic(true);
+    let root = Dir::load_with(txn_id, tmp.cache(), options).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+    Ok((root, file))
+}
+
+#[tokio::test]
+async fn test_read_then_earlier_write() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&tmp, &txn_ids).await?;
+
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+
+    assert_eq!(*file.read::<Text>(later).await?, Text::from("hello"));
+
+    // the earlier transaction doesn't wait for the later read to write
+    file.write::<Text>(earlier).await?.0 = "goodbye".to_string();
+
+    // but it can't be committed, since the later transaction should have read the new version
+    assert!(matches!(
+        root.commit(earlier, true).await,
+        Err(Error::Conflict(_))
+    ));
+
+    root.rollback(earlier, true).await;
+    root.commit(later, true).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upgrade_read() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&tmp, &txn_ids).await?;
+
+    // a read in optimistic mode holds no lock, so it can always be upgraded
+    let txn_id = txn_ids.next();
+    let version = file.read::<Text>(txn_id).await?;
+    version.upgrade().await?.0 = "goodbye".to_string();
+
+    root.commit(txn_id, true).await?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("goodbye"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_writes() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&tmp, &txn_ids).await?;
+
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+
+    file.write::<Text>(later).await?.0 = "later".to_string();
+    file.write::<Text>(earlier).await?.0 = "earlier".to_string();
+
+    root.commit(later, true).await?;
+
+    // the later transaction didn't see the earlier write, so the earlier one has to give way
+    assert!(matches!(
+        root.commit(earlier, true).await,
+        Err(Error::Conflict(_))
+    ));
+
+    root.rollback(earlier, true).await;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("later"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_commit_after_earlier_create() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&tmp, &txn_ids).await?;
+
+    let earlier = txn_ids.next();
+    let later = txn_ids.next();
+
+    root.create_file(earlier, id("new"), Text::from("new"))
+        .await?;
+
+    file.write::<Text>(later).await?.0 = "later".to_string();
+
+    // the later commit has to wait for the earlier transaction, which is still pending...
+    let commit = {
+        let root = root.clone();
+        tokio::spawn(async move { root.commit(later, true).await })
+    };
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!commit.is_finished());
+
+    // ...but it doesn't keep the earlier transaction from creating another file, nor committing
+    root.create_file(earlier, id("other"), Text::from("other"))
+        .await?;
+
+    tokio::time::timeout(Duration::from_secs(1), root.commit(earlier, true))
+        .await
+        .expect("earlier commit")?;
+
+    commit.await.expect("later commit")?;
+
+    let txn_id = txn_ids.next();
+    assert_eq!(*file.read::<Text>(txn_id).await?, Text::from("later"));
+    assert_eq!(root.len(txn_id).await?, 3);
+
+    Ok(())
+}
@@ -0,0 +1,216 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use common::*;
+use txfs::{run_txn_with, Dir, DirOptions, Error, RetryPolicy, TxnIdSource};
+
+type TxnFile = txfs::File<TxnId, File>;
+
+async fn setup(
+    txn_ids: &TxnIdSource<TxnId>,
+    tmp: &TmpDir,
+    options: DirOptions<File>,
+) -> Result<(Dir<TxnId, File>, TxnFile), Error> {
+    let txn_id = txn_ids.next();
+    let root = Dir::<TxnId, File>::load_with(txn_id, tmp.cache(), options).await?;
+    let file = root
+        .create_file(txn_id, id("text"), Text::from("hello"))
+        .await?;
+
+    root.commit(txn_id, true).await?;
+
+    Ok((root, file))
+}
+
+fn policy() -> RetryPolicy {
+    RetryPolicy::default().initial_backoff(Duration::from_millis(1))
+}
+
+#[tokio::test]
+async fn test_retry_conflict() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&txn_ids, &tmp, DirOptions::default()).await?;
+
+    // the first attempt writes at a transaction earlier than a later read, so it's outdated
+    let stale = Mutex::new(Some(txn_ids.next()));
+    let later = txn_ids.next();
+    file.read::<Text>(later).await?;
+
+    let ids = || {
+        stale
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| txn_ids.next())
+    };
+    let attempts = AtomicUsize::new(0);
+
+    let txn_id = run_txn_with(&root, ids, policy(), |txn_id| {
+        attempts.fetch_add(1, Ordering::Relaxed);
+        let file = file.clone();
+
+        async move {
+            file.write::<Text>(txn_id).await?.0.push_str(", world");
+            Ok(txn_id)
+        }
+    })
+    .await?;
+
+    assert_eq!(attempts.load(Ordering::Relaxed), 2);
+    assert!(txn_id > later);
+    assert_eq!(
+        *file.read::<Text>(txn_ids.next()).await?,
+        Text::from("hello, world")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_retry_timeout() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let options = DirOptions::default().lock_timeout(Duration::from_millis(20));
+    let (root, file) = setup(&txn_ids, &tmp, options).await?;
+
+    // a pending write in an earlier transaction blocks the first attempt until it times out
+    let blocker = txn_ids.next();
+    let guard = Mutex::new(Some(file.write::<Text>(blocker).await?));
+    let attempts = AtomicUsize::new(0);
+
+    let contents = run_txn_with(
+        &root,
+        || txn_ids.next(),
+        policy(),
+        |txn_id| {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            let file = file.clone();
+            let root = root.clone();
+            let guard = &guard;
+
+            async move {
+                match file.read::<Text>(txn_id).await {
+                    Ok(contents) => Ok(contents.clone()),
+                    Err(cause) => {
+                        std::mem::drop(guard.lock().unwrap().take());
+                        root.rollback(blocker, true).await;
+                        Err(cause)
+                    }
+                }
+            }
+        },
+    )
+    .await?;
+
+    assert_eq!(attempts.load(Ordering::Relaxed), 2);
+    assert_eq!(contents, Text::from("hello"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_attempts() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&txn_ids, &tmp, DirOptions::default()).await?;
+
+    // every attempt writes at a transaction earlier than a later read
+    let stale = Mutex::new(vec![txn_ids.next(), txn_ids.next(), txn_ids.next()]);
+    file.read::<Text>(txn_ids.next()).await?;
+
+    let ids = || stale.lock().unwrap().remove(0);
+    let attempts = AtomicUsize::new(0);
+
+    let result = run_txn_with(&root, ids, policy().max_attempts(3), |txn_id| {
+        attempts.fetch_add(1, Ordering::Relaxed);
+        let file = file.clone();
+
+        async move { file.write::<Text>(txn_id).await.map(|_version| ()) }
+    })
+    .await;
+
+    assert!(matches!(result, Err(Error::Conflict(_))));
+    assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_no_retry() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, _file) = setup(&txn_ids, &tmp, DirOptions::default()).await?;
+
+    let attempts = AtomicUsize::new(0);
+    let attempted = Mutex::new(None);
+
+    let result = run_txn_with(
+        &root,
+        || txn_ids.next(),
+        policy(),
+        |txn_id| {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            *attempted.lock().unwrap() = Some(txn_id);
+            let root = root.clone();
+
+            async move {
+                root.create_file(txn_id, id("new"), Text::from("new"))
+                    .await?;
+
+                root.create_file(txn_id, id("text"), Text::from("again"))
+                    .await
+                    .map(|_file| ())
+            }
+        },
+    )
+    .await;
+
+    // an error which isn't retryable is returned after the first attempt...
+    assert!(matches!(result, Err(Error::AlreadyExists(_))));
+    assert_eq!(attempts.load(Ordering::Relaxed), 1);
+
+    // ...whose transaction is rolled back
+    let txn_id = attempted.lock().unwrap().expect("attempt");
+    assert_eq!(root.memory_usage(txn_id), 0);
+    assert!(root.get_file(txn_ids.next(), &id("new")).await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_backoff_overflow() -> Result<(), Error> {
+    let tmp = TmpDir::new();
+    let txn_ids = TxnIdSource::<TxnId>::default();
+    let (root, file) = setup(&txn_ids, &tmp, DirOptions::default()).await?;
+
+    let stale = Mutex::new((0..5).map(|_| txn_ids.next()).collect::<Vec<_>>());
+    file.read::<Text>(txn_ids.next()).await?;
+
+    // a backoff which grows past the maximum duration saturates rather than panicking
+    let policy = RetryPolicy::default()
+        .max_attempts(5)
+        .initial_backoff(Duration::from_secs(1))
+        .max_backoff(Duration::MAX)
+        .multiplier(u32::MAX);
+
+    tokio::time::pause();
+
+    let result = run_txn_with(
+        &root,
+        || stale.lock().unwrap().remove(0),
+        policy,
+        |txn_id| {
+            let file = file.clone();
+            async move { file.write::<Text>(txn_id).await.map(|_version| ()) }
+        },
+    )
+    .await;
+
+    assert!(matches!(result, Err(Error::Conflict(_))));
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+use txfs::{CounterTxnId, Error, HlcTxnId, NextTxnId, NodeTxnId, TxnIdSource};
+
+// check that `txn_ids` round-trip through their names and that their names sort in the same order
+fn check_names<T>(txn_ids: &[T])
+where
+    T: NextTxnId + FromStr<Err = Error> + PartialOrd<str> + fmt::Debug + fmt::Display,
+{
+    for txn_id in txn_ids {
+        let name = txn_id.to_string();
+        assert_eq!(&name.parse::<T>().expect("txn ID"), txn_id);
+        assert!(*txn_id == *name.as_str());
+        assert!(*txn_id <= *name.as_str());
+    }
+
+    for (i, left) in txn_ids.iter().enumerate() {
+        for right in &txn_ids[i + 1..] {
+            assert!(left < right, "{left:?} should precede {right:?}");
+            assert!(left.to_string() < right.to_string());
+            assert!(*left < *right.to_string().as_str());
+            assert!(*right > *left.to_string().as_str());
+        }
+    }
+}
+
+#[test]
+fn test_counter_names() {
+    check_names(&[
+        CounterTxnId::new(0),
+        CounterTxnId::new(1),
+        CounterTxnId::new(9),
+        CounterTxnId::new(10),
+        CounterTxnId::new(1 << 32),
+        CounterTxnId::new(u64::MAX),
+    ]);
+
+    assert_eq!(CounterTxnId::new(9).to_string().len(), 20);
+    assert!("".parse::<CounterTxnId>().is_err());
+    assert!("-1".parse::<CounterTxnId>().is_err());
+    assert!("text".parse::<CounterTxnId>().is_err());
+}
+
+#[test]
+fn test_hlc_names() {
+    check_names(&[
+        HlcTxnId::new(0, 0),
+        HlcTxnId::new(0, 9),
+        HlcTxnId::new(0, 10),
+        HlcTxnId::new(0, u32::MAX),
+        HlcTxnId::new(1, 0),
+        HlcTxnId::new(10, 1),
+        HlcTxnId::new(u64::MAX, u32::MAX),
+    ]);
+
+    assert!("00000000000000000001".parse::<HlcTxnId>().is_err());
+    assert!("1-".parse::<HlcTxnId>().is_err());
+    assert!("1-99999999999".parse::<HlcTxnId>().is_err());
+}
+
+#[test]
+fn test_node_names() {
+    check_names(&[
+        NodeTxnId::new(0, 0),
+        NodeTxnId::new(0, 1),
+        NodeTxnId::new(1, 0),
+        NodeTxnId::new(10, 0),
+        NodeTxnId::new(u64::MAX, 0),
+        NodeTxnId::new(u64::MAX, u32::MAX),
+    ]);
+
+    assert!("1+2".parse::<NodeTxnId>().is_err());
+}
+
+// check that `source` issues unique, increasing IDs to several threads at once
+fn check_source<T>(source: TxnIdSource<T>)
+where
+    T: NextTxnId + Eq + std::hash::Hash + fmt::Debug + Send + 'static,
+{
+    const THREADS: usize = 8;
+    const IDS: usize = 1000;
+
+    let source = Arc::new(source);
+
+    let threads = (0..THREADS)
+        .map(|_| {
+            let source = source.clone();
+            thread::spawn(move || (0..IDS).map(|_| source.next()).collect::<Vec<T>>())
+        })
+        .collect::<Vec<_>>();
+
+    let mut unique = HashSet::new();
+
+    for thread in threads {
+        let txn_ids = thread.join().expect("thread");
+        assert!(txn_ids.windows(2).all(|pair| pair[0] < pair[1]));
+        unique.extend(txn_ids);
+    }
+
+    assert_eq!(unique.len(), THREADS * IDS);
+
+    let last = unique.into_iter().max().expect("last txn ID");
+    assert!(source.next() > last);
+}
+
+#[test]
+fn test_source_concurrent() {
+    check_source(TxnIdSource::<CounterTxnId>::default());
+    check_source(TxnIdSource::<HlcTxnId>::default());
+    check_source(TxnIdSource::<NodeTxnId>::new(NodeTxnId::new(0, 1)));
+}
+
+#[test]
+fn test_source_observe() {
+    let source = TxnIdSource::<HlcTxnId>::default();
+
+    // an ID observed from another source, e.g. with a clock ahead of this one
+    let observed = HlcTxnId::new(u64::MAX - 1, u32::MAX);
+    source.observe(observed);
+    assert!(source.next() > observed);
+
+    // observing an earlier ID has no effect
+    let last = source.next();
+    source.observe(HlcTxnId::new(1, 0));
+    assert!(source.next() > last);
+}
+
+#[test]
+fn test_source_observe_node() {
+    let source = TxnIdSource::<NodeTxnId>::new(NodeTxnId::new(0, 1));
+
+    // an ID observed from another node keeps the ID of this node
+    let observed = NodeTxnId::new(u64::MAX - 1, 2);
+    source.observe(observed);
+
+    let next = source.next();
+    assert!(next > observed);
+    assert_eq!(next, NodeTxnId::new(u64::MAX, 1));
+}
+
+#[test]
+#[should_panic(expected = "transaction ID overflow")]
+fn test_counter_overflow() {
+    CounterTxnId::new(u64::MAX).next();
+}
+
+#[test]
+#[should_panic(expected = "transaction ID overflow")]
+fn test_node_overflow() {
+    NodeTxnId::new(u64::MAX, 1).next();
+}